@@ -4,8 +4,30 @@ from sklearn.model_selection import train_test_split
 import matplotlib.pyplot as plt
 from sklearn import linear_model, metrics
 
-f = './train_data/train_data_combined.csv'
-df = pd.read_csv(f)
+import argparse
+from pathlib import Path
+
+# Columns of the CSV files written by `rustymind-learn --format csv`, schema version 2
+VERSION = 2
+COLUMNS = ['version', 'record', 'timestamp', 'monotonic', 'window_start', 'raw_expected',
+           'raw_received', 'attention', 'meditation', 'poor_signal', 'delta', 'theta',
+           'low_alpha', 'high_alpha', 'low_beta', 'high_beta', 'low_gamma', 'mid_gamma']
+
+# the same --delimiter and --no-header as rustymind-learn wrote the files with
+parser = argparse.ArgumentParser(description='Fits attention to the band powers')
+parser.add_argument('--delimiter', default=',', help='CSV field delimiter, a single character or tab')
+parser.add_argument('--no-header', action='store_true', help='the CSV files have no header row')
+args = parser.parse_args()
+sep = '\t' if args.delimiter == 'tab' else args.delimiter
+
+paths = [p for p in Path('./train_data').glob('*.csv') if not p.stem.endswith('_raw')]
+df = pd.concat([
+    pd.read_csv(p, sep=sep, header=None if args.no_header else 0, names=COLUMNS)
+    for p in paths
+], ignore_index=True)
+df = df[df['version'] == VERSION]
+# attention or meditation equals 0 indicating low reliability
+df = df[(df['poor_signal'] == 0) & (df['attention'] != 0) & (df['meditation'] != 0)]
 print(df.head())
 print(list(df.columns))
 
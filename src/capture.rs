@@ -0,0 +1,368 @@
+use log::warn;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// A capture file starts with these magic bytes, a version byte and, since version 2, the wall
+// clock time the capture started in microseconds since the Unix epoch as a LEB128 varint. One
+// record per chunk read from the port follows: the microseconds since the previous chunk, or
+// since the capture started for the first one, the chunk length, both as LEB128 varints, and
+// the chunk bytes.
+const MAGIC: &[u8; 5] = b"RMCAP";
+const VERSION: u8 = 2;
+
+// Transport recording every chunk read from the inner transport, with the time it was read,
+// to a capture file. Commands written to it are passed on and not recorded.
+pub struct Capture<T, W> {
+    inner: T,
+    writer: W,
+    last: Instant,
+}
+
+impl<T, W: Write> Capture<T, W> {
+    pub fn new(inner: T, mut writer: W) -> io::Result<Capture<T, W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write_varint(&mut writer, started.as_micros() as u64)?;
+        writer.flush()?;
+        Ok(Capture {
+            inner,
+            writer,
+            last: Instant::now(),
+        })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let now = Instant::now();
+        write_varint(
+            &mut self.writer,
+            now.duration_since(self.last).as_micros() as u64,
+        )?;
+        write_varint(&mut self.writer, chunk.len() as u64)?;
+        self.writer.write_all(chunk)?;
+        // a capture is most useful after a crash, so keep the file complete
+        self.writer.flush()?;
+        self.last = now;
+        Ok(())
+    }
+}
+
+impl<T: Read, W: Write> Read for Capture<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.record(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Write, W> Write for Capture<T, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// How fast a capture is replayed
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Speed {
+    // Multiple of the original speed, 1.0 replaying chunks at the times they were read
+    Factor(f64),
+    // As fast as the reader takes the bytes
+    Max,
+}
+
+impl Default for Speed {
+    fn default() -> Speed {
+        Speed::Factor(1.0)
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Speed, String> {
+        if s == "max" {
+            return Ok(Speed::Max);
+        }
+        match s.parse::<f64>() {
+            Ok(factor) if factor > 0.0 && factor.is_finite() => Ok(Speed::Factor(factor)),
+            _ => Err(format!(
+                "invalid speed {}, expected a positive factor or max",
+                s
+            )),
+        }
+    }
+}
+
+// Reads the bytes of a capture file back in the chunks they were captured in, paced by the
+// capture timestamps. Returns end of stream after the last chunk.
+pub struct Replay<R> {
+    reader: R,
+    speed: Speed,
+    start: Option<Instant>,
+    started: Option<SystemTime>,
+    // capture time of the current chunk
+    elapsed: Duration,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Replay<R> {
+    pub fn new(mut reader: R, speed: Speed) -> io::Result<Replay<R>> {
+        let mut header = [0; 6];
+        reader.read_exact(&mut header)?;
+        if &header[..5] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a capture file",
+            ));
+        }
+        let started = match header[5] {
+            // version 1 did not record when the capture started
+            1 => None,
+            VERSION => match read_varint(&mut reader)? {
+                Some(micros) => Some(UNIX_EPOCH + Duration::from_micros(micros)),
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            },
+            version => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported capture version {}", version),
+                ))
+            }
+        };
+        Ok(Replay {
+            reader,
+            speed,
+            start: None,
+            started,
+            elapsed: Duration::from_secs(0),
+            chunk: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    // Wall clock time the capture started, unknown for version 1 captures. The replay starts
+    // with its first read.
+    pub fn started(&self) -> Option<SystemTime> {
+        self.started
+    }
+
+    // Reads the next chunk, returns false at the end of the capture
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        let delta = match read_varint(&mut self.reader)? {
+            Some(delta) => delta,
+            None => return Ok(false),
+        };
+        let len = match read_varint(&mut self.reader)? {
+            Some(len) => len as usize,
+            None => return truncated(),
+        };
+        self.chunk.resize(len, 0);
+        if let Err(e) = self.reader.read_exact(&mut self.chunk) {
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof => truncated(),
+                _ => Err(e),
+            };
+        }
+        self.pos = 0;
+        self.elapsed += Duration::from_micros(delta);
+        Ok(true)
+    }
+
+    fn pace(&mut self) {
+        if let Speed::Factor(factor) = self.speed {
+            let start = *self.start.get_or_insert_with(Instant::now);
+            let due = start + self.elapsed.div_f64(factor);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Replay<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+            self.pace();
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// A capture cut short, by a crash for instance, ends with the last complete chunk
+fn truncated() -> io::Result<bool> {
+    warn!("capture ends in the middle of a chunk");
+    Ok(false)
+}
+
+fn write_varint<W: Write + ?Sized>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    let mut buf = [0; 10];
+    let mut n = 0;
+    loop {
+        buf[n] = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+        buf[n] |= 0x80;
+        n += 1;
+    }
+    writer.write_all(&buf[..=n])
+}
+
+// None at the end of the stream before the first byte
+fn read_varint<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<u64>> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            return match shift {
+                0 => Ok(None),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint longer than 64 bits",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReadWrite;
+    use pretty_assertions::assert_eq;
+
+    // Reads `data` in chunks of the given sizes
+    struct Chunked<'a> {
+        data: &'a [u8],
+        sizes: Vec<usize>,
+    }
+
+    impl<'a> Read for Chunked<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.sizes.is_empty() {
+                return Ok(0);
+            }
+            let n = self.sizes.remove(0).min(buf.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_capture_replay() {
+        let data: Vec<u8> = (0..=255).collect();
+        let port = ReadWrite::new(
+            Chunked {
+                data: &data,
+                sizes: vec![3, 200, 53],
+            },
+            Vec::new(),
+        );
+        let mut capture = Capture::new(port, Vec::new()).unwrap();
+        let mut buf = [0; 256];
+        let mut read = Vec::new();
+        loop {
+            let n = capture.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        capture.write_all(&[0xc2]).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(capture.get_ref().writer, vec![0xc2]);
+
+        let file = capture.writer;
+        assert_eq!(&file[..6], b"RMCAP\x02");
+        let mut replay = Replay::new(&file[..], Speed::Max).unwrap();
+        let started = replay.started().unwrap().elapsed().unwrap();
+        assert!(started < Duration::from_secs(5));
+        let mut chunks = Vec::new();
+        loop {
+            let n = replay.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            chunks.push(buf[..n].to_vec());
+        }
+        assert_eq!(
+            chunks,
+            vec![
+                data[..3].to_vec(),
+                data[3..203].to_vec(),
+                data[203..].to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_pacing() {
+        // two chunks 40 ms apart and a third one cut short
+        let mut file = b"RMCAP\x01".to_vec();
+        file.extend_from_slice(&[0x00, 0x02, 0xaa, 0xaa]);
+        file.extend_from_slice(&[0xc0, 0xb8, 0x02, 0x01, 0x04]);
+        file.extend_from_slice(&[0x00, 0x03, 0x0d]);
+
+        let start = Instant::now();
+        let mut replay = Replay::new(&file[..], Speed::Factor(2.0)).unwrap();
+        let mut buf = Vec::new();
+        replay.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, vec![0xaa, 0xaa, 0x04]);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        assert_eq!(replay.started(), None);
+
+        let mut file = b"RMCAP\x02".to_vec();
+        write_varint(&mut file, 1_792_281_600_000_000).unwrap();
+        let replay = Replay::new(&file[..], Speed::Max).unwrap();
+        assert_eq!(
+            replay.started(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_792_281_600))
+        );
+        assert!(Replay::new(&b"RMCAP\x02"[..], Speed::Max).is_err());
+        assert!(Replay::new(&b"RMCAP\x03"[..], Speed::Max).is_err());
+        assert!(Replay::new(&b"AA AA\x01"[..], Speed::Max).is_err());
+    }
+
+    #[test]
+    fn test_speed() {
+        assert_eq!("max".parse(), Ok(Speed::Max));
+        assert_eq!("4".parse(), Ok(Speed::Factor(4.0)));
+        assert_eq!("0.5".parse(), Ok(Speed::Factor(0.5)));
+        assert!("0".parse::<Speed>().is_err());
+        assert!("fast".parse::<Speed>().is_err());
+    }
+}
@@ -0,0 +1,198 @@
+// Command line arguments choosing the session source, shared by the binaries reading a headset
+use clap::{App, Arg, ArgMatches};
+use hex::decode;
+use rustymind::{
+    find_dongle, Error, Event, Source, Speed, Supervisor, Transport, HEADSETID_AUTOCONNECT,
+};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Session source and options given on the command line
+pub struct SessionArgs {
+    pub source: Source,
+    pub headset: Vec<u8>,
+    pub capture: Option<PathBuf>,
+    pub stats: Option<Duration>,
+    pub max_attempts: Option<u32>,
+}
+
+impl SessionArgs {
+    // Looks for the dongle with --auto
+    pub fn from_matches(matches: &ArgMatches) -> Result<SessionArgs, Error> {
+        let headset = matches
+            .value_of("HEADSET_ID")
+            .or_else(|| matches.value_of("headset"))
+            .map_or(HEADSETID_AUTOCONNECT.to_vec(), |v| decode(v).unwrap());
+        let source = if let Some(capture) = matches.value_of("replay") {
+            let speed = matches
+                .value_of("speed")
+                .map_or(Speed::default(), |v| v.parse().unwrap());
+            Source::Replay(capture.into(), speed)
+        } else if let Some(file) = matches.value_of("file") {
+            Source::File(file.into())
+        } else if matches.is_present("stdin") {
+            Source::Stdin
+        } else if matches.is_present("auto") {
+            Source::Serial(find_dongle(true)?)
+        } else {
+            Source::Serial(matches.value_of("dongle-path").unwrap().to_string())
+        };
+        Ok(SessionArgs {
+            source,
+            headset,
+            capture: matches.value_of("capture").map(PathBuf::from),
+            stats: matches
+                .value_of("stats")
+                .map(|v| Duration::from_secs(v.parse().unwrap())),
+            max_attempts: matches.value_of("max-attempts").map(|v| v.parse().unwrap()),
+        })
+    }
+
+    // Opens the supervised session, recording it with --capture, and reads it on a worker
+    // thread so that reconnecting never holds up the caller. The events arrive on the returned
+    // channel, followed by the error ending the session if any, and the parser statistics are
+    // printed every --stats interval. The thread stops once the receiver is dropped.
+    pub fn spawn(&self) -> Result<Receiver<Result<Event, Error>>, Error> {
+        let mut session = self.supervise()?;
+        let stats = self.stats;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stats_time = Instant::now();
+            loop {
+                let event = match session.next_event() {
+                    Ok(Some(event)) => Ok(event),
+                    Ok(None) => break,
+                    Err(e) => Err(e),
+                };
+                let failed = event.is_err();
+                if sender.send(event).is_err() || failed {
+                    break;
+                }
+                if let Some(interval) = stats {
+                    if stats_time.elapsed() >= interval {
+                        eprintln!("{}", session.stats());
+                        stats_time = Instant::now();
+                    }
+                }
+            }
+        });
+        Ok(receiver)
+    }
+
+    fn supervise(&self) -> Result<Supervisor<Box<dyn Transport>>, Error> {
+        let supervisor = match &self.capture {
+            Some(path) => self.source.capture(&self.headset, path)?,
+            None => self.source.supervise(&self.headset)?,
+        };
+        Ok(match self.max_attempts {
+            Some(attempts) => supervisor.max_attempts(attempts),
+            None => supervisor,
+        })
+    }
+}
+
+// Adds the arguments choosing the session source. `alternatives` names further arguments of
+// the binary that make the dongle path unnecessary.
+pub fn source_args<'a, 'b>(app: App<'a, 'b>, alternatives: &[&'a str]) -> App<'a, 'b> {
+    let mut unless = vec!["file", "stdin", "auto", "replay"];
+    unless.extend_from_slice(alternatives);
+    app.arg(
+        Arg::with_name("dongle-path")
+            .help("Sets the dongle path")
+            .required_unless_one(&unless),
+    )
+    .arg(
+        Arg::with_name("HEADSET_ID")
+            .requires("dongle-path")
+            .validator(headset_id)
+            .help("Sets the headset ID. Set headset ID to 0xc2 to switch into auto-connect mode and connect to any to any headsets dongle can find"),
+    )
+    .arg(
+        Arg::with_name("headset")
+            .long("headset")
+            .value_name("HEADSET_ID")
+            .takes_value(true)
+            .conflicts_with("HEADSET_ID")
+            .validator(headset_id)
+            .help("Sets the headset ID when no dongle path is given, as with --auto"),
+    )
+    .arg(
+        Arg::with_name("file")
+            .long("file")
+            .value_name("PATH")
+            .takes_value(true)
+            .conflicts_with("stdin")
+            .help("Reads a recorded byte stream instead of the dongle"),
+    )
+    .arg(
+        Arg::with_name("stdin")
+            .long("stdin")
+            .help("Reads the byte stream from stdin instead of the dongle"),
+    )
+    .arg(
+        Arg::with_name("auto")
+            .long("auto")
+            .conflicts_with_all(&["dongle-path", "file", "stdin"])
+            .help("Finds the dongle among the serial ports"),
+    )
+    .arg(
+        Arg::with_name("replay")
+            .long("replay")
+            .value_name("PATH")
+            .takes_value(true)
+            .conflicts_with_all(&["file", "stdin", "auto"])
+            .help("Replays a capture file instead of reading the dongle"),
+    )
+    .arg(
+        Arg::with_name("speed")
+            .long("speed")
+            .value_name("FACTOR")
+            .takes_value(true)
+            .requires("replay")
+            .validator(|v| v.parse::<Speed>().map(|_| ()))
+            .help("Replays at FACTOR times the original speed, or as fast as possible with max"),
+    )
+    .arg(
+        Arg::with_name("capture")
+            .long("capture")
+            .value_name("PATH")
+            .takes_value(true)
+            .help("Writes the bytes read from the source to a capture file for --replay"),
+    )
+    .arg(
+        Arg::with_name("stats")
+            .long("stats")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .validator(number)
+            .help("Prints parser statistics every SECONDS seconds"),
+    )
+    .arg(
+        Arg::with_name("max-attempts")
+            .long("max-attempts")
+            .value_name("COUNT")
+            .takes_value(true)
+            .validator(|v| v.parse::<u32>().map_err(|e| e.to_string()).and(number(v)))
+            .help("Gives up after COUNT failed reconnect attempts in a row instead of retrying forever"),
+    )
+}
+
+// Validates a positive whole number
+pub fn number(v: String) -> Result<(), String> {
+    match v.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("{} is not a positive whole number", v)),
+    }
+}
+
+fn headset_id(v: String) -> Result<(), String> {
+    match decode(&v) {
+        Ok(id) if id.len() == 2 || id == HEADSETID_AUTOCONNECT => Ok(()),
+        _ => Err(format!(
+            "{} is not a headset ID, expected four hex digits or c2",
+            v
+        )),
+    }
+}
@@ -0,0 +1,357 @@
+use crate::{Dongle, DongleStatus, Error, Transport};
+use log::info;
+use serialport::SerialPort;
+use std::thread;
+use std::time::Duration;
+
+// Output selection command bytes: page 1 switches poor signal, EEG powers, attention and
+// meditation on or off, one bit each, and page 2 the raw wave
+const PAGE_OUTPUTS: u8 = 0x10;
+const PAGE_RAW: u8 = 0x20;
+const ALL_OUTPUTS: u8 = 0x0f;
+const RAW_WAVE: u8 = 0x01;
+
+// Configuration bytes a ThinkGear module accepts on its serial line. The module switches its
+// output mode, and for some commands its baud rate, right after receiving them.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ConfigCommand {
+    // 9600 baud, normal output: eSense values and EEG powers, no raw wave
+    Normal9600,
+    // 1200 baud, normal output
+    Normal1200,
+    // 57600 baud, normal output plus the raw wave
+    Raw57600,
+    // Raw wave output on or off, the other outputs and the baud rate unchanged
+    RawOutput(bool),
+    // Normal output mode: poor signal, EEG powers, attention and meditation on, raw wave off
+    NormalOutput,
+    // Raw-only output mode: the raw wave on, everything else off. Needs a baud rate fast
+    // enough for 512 samples per second, such as after Raw57600.
+    RawOnlyOutput,
+    // Any other configuration byte. The baud rate of the serial port is left alone.
+    Byte(u8),
+}
+
+impl ConfigCommand {
+    // Bytes sent to the module, in order
+    pub fn bytes(&self) -> Vec<u8> {
+        match self {
+            ConfigCommand::Normal9600 => vec![0x00],
+            ConfigCommand::Normal1200 => vec![0x01],
+            ConfigCommand::Raw57600 => vec![0x02],
+            ConfigCommand::RawOutput(true) => vec![PAGE_RAW | RAW_WAVE],
+            ConfigCommand::RawOutput(false) => vec![PAGE_RAW],
+            ConfigCommand::NormalOutput => vec![PAGE_OUTPUTS | ALL_OUTPUTS, PAGE_RAW],
+            ConfigCommand::RawOnlyOutput => vec![PAGE_OUTPUTS, PAGE_RAW | RAW_WAVE],
+            ConfigCommand::Byte(byte) => vec![*byte],
+        }
+    }
+
+    // Baud rate the module talks at after the command, None when it keeps its rate
+    pub fn baud_rate(&self) -> Option<u32> {
+        match self {
+            ConfigCommand::Normal9600 => Some(9600),
+            ConfigCommand::Normal1200 => Some(1200),
+            ConfigCommand::Raw57600 => Some(57_600),
+            _ => None,
+        }
+    }
+}
+
+// Opens the serial port of a dongle or ThinkGear module. The MindWave dongle talks at
+// 115200 baud; older MindSet and ThinkGear modules at 9600 or 57600.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ConnectionBuilder {
+    path: String,
+    baud_rate: u32,
+    timeout: Duration,
+    commands: Vec<ConfigCommand>,
+}
+
+impl ConnectionBuilder {
+    pub fn new(path: &str) -> ConnectionBuilder {
+        ConnectionBuilder {
+            path: path.to_string(),
+            baud_rate: 115_200,
+            timeout: Duration::from_secs(10),
+            commands: Vec::new(),
+        }
+    }
+
+    // Sets the baud rate the port is opened at
+    pub fn baud_rate(mut self, baud_rate: u32) -> ConnectionBuilder {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    // Sets how long to wait for the dongle to answer a command. Reads from the port time out
+    // after at most a second, so that the wait ends in time.
+    pub fn timeout(mut self, timeout: Duration) -> ConnectionBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    // Sends a configuration command once the port is open, following the module to its new
+    // baud rate. Commands are sent in the order they are added.
+    pub fn config(mut self, command: ConfigCommand) -> ConnectionBuilder {
+        self.commands.push(command);
+        self
+    }
+
+    // Opens the port and sends the configuration commands
+    pub fn open(&self) -> Result<Dongle<Box<dyn Transport>>, Error> {
+        Ok(self.dongle(Box::new(self.open_port()?)))
+    }
+
+    pub(crate) fn open_port(&self) -> Result<Box<dyn SerialPort>, Error> {
+        let port = serialport::new(&self.path, self.baud_rate)
+            .timeout(self.timeout.min(Duration::from_secs(1)))
+            .open()?;
+        self.configure(port)
+    }
+
+    fn configure(&self, mut port: Box<dyn SerialPort>) -> Result<Box<dyn SerialPort>, Error> {
+        for command in self.commands.iter() {
+            configure(&mut *port, *command)?;
+        }
+        Ok(port)
+    }
+
+    fn dongle<T: Transport>(&self, transport: T) -> Dongle<T> {
+        Dongle::new(transport).timeout(self.timeout)
+    }
+
+    // Opens the dongle and connects to `headset`, or to any headset when given
+    // HEADSETID_AUTOCONNECT, once the dongle confirms the connection
+    pub fn connect(&self, headset: &[u8]) -> Result<Box<dyn Transport>, Error> {
+        let mut dongle = self.open()?;
+        dongle.disconnect()?;
+        match dongle.connect_headset(headset)? {
+            DongleStatus::Connected(_) => Ok(Box::new(dongle)),
+            DongleStatus::Denied => Err(Error::Denied),
+            DongleStatus::NotFound(id) => Err(Error::NotFound(id)),
+            status => Err(Error::UnexpectedStatus(status)),
+        }
+    }
+}
+
+// Opens the dongle and connects to `headset`, or to any headset when given
+// HEADSETID_AUTOCONNECT, once the dongle confirms the connection
+pub fn connect_headset(path: &str, headset: &[u8]) -> Result<Box<dyn Transport>, Error> {
+    ConnectionBuilder::new(path).connect(headset)
+}
+
+// Opens the dongle's serial port without sending any command
+pub fn open_dongle(path: &str) -> Result<Dongle<Box<dyn Transport>>, Error> {
+    ConnectionBuilder::new(path).open()
+}
+
+fn configure(port: &mut dyn SerialPort, command: ConfigCommand) -> Result<(), Error> {
+    info!("sending configuration command {:?}", command);
+    port.write_all(&command.bytes())?;
+    port.flush()?;
+    if let Some(baud_rate) = command.baud_rate() {
+        // give the module time to switch before following it
+        thread::sleep(Duration::from_millis(50));
+        port.set_baud_rate(baud_rate)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serialport::{ClearBuffer, DataBits, FlowControl, Parity, StopBits};
+    use std::io::{self, Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    // Serial port keeping what is written to it and its baud rate, never answering
+    #[derive(Default)]
+    struct Line {
+        written: Vec<u8>,
+        // baud rate in effect when each byte was written
+        rates: Vec<u32>,
+        baud_rate: u32,
+    }
+
+    #[derive(Clone)]
+    struct MockPort(Arc<Mutex<Line>>);
+
+    impl Read for MockPort {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(5));
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut line = self.0.lock().unwrap();
+            let rate = line.baud_rate;
+            line.written.extend_from_slice(buf);
+            line.rates.extend(buf.iter().map(|_| rate));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SerialPort for MockPort {
+        fn name(&self) -> Option<String> {
+            None
+        }
+        fn baud_rate(&self) -> serialport::Result<u32> {
+            Ok(self.0.lock().unwrap().baud_rate)
+        }
+        fn data_bits(&self) -> serialport::Result<DataBits> {
+            Ok(DataBits::Eight)
+        }
+        fn flow_control(&self) -> serialport::Result<FlowControl> {
+            Ok(FlowControl::None)
+        }
+        fn parity(&self) -> serialport::Result<Parity> {
+            Ok(Parity::None)
+        }
+        fn stop_bits(&self) -> serialport::Result<StopBits> {
+            Ok(StopBits::One)
+        }
+        fn timeout(&self) -> Duration {
+            Duration::from_millis(5)
+        }
+        fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+            self.0.lock().unwrap().baud_rate = baud_rate;
+            Ok(())
+        }
+        fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_timeout(&mut self, _: Duration) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+        fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn bytes_to_read(&self) -> serialport::Result<u32> {
+            Ok(0)
+        }
+        fn bytes_to_write(&self) -> serialport::Result<u32> {
+            Ok(0)
+        }
+        fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+            Ok(Box::new(self.clone()))
+        }
+        fn set_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn clear_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_config_command() {
+        let commands = [
+            ConfigCommand::Normal9600,
+            ConfigCommand::Normal1200,
+            ConfigCommand::Raw57600,
+            ConfigCommand::RawOutput(true),
+            ConfigCommand::RawOutput(false),
+            ConfigCommand::NormalOutput,
+            ConfigCommand::RawOnlyOutput,
+            ConfigCommand::Byte(0x1f),
+        ];
+        assert_eq!(
+            commands
+                .iter()
+                .map(ConfigCommand::bytes)
+                .collect::<Vec<_>>(),
+            vec![
+                vec![0x00],
+                vec![0x01],
+                vec![0x02],
+                vec![0x21],
+                vec![0x20],
+                vec![0x1f, 0x20],
+                vec![0x10, 0x21],
+                vec![0x1f]
+            ]
+        );
+        assert_eq!(
+            commands
+                .iter()
+                .map(ConfigCommand::baud_rate)
+                .collect::<Vec<_>>(),
+            vec![
+                Some(9600),
+                Some(1200),
+                Some(57_600),
+                None,
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_configure_port() {
+        let line = Arc::new(Mutex::new(Line {
+            baud_rate: 9600,
+            ..Line::default()
+        }));
+        let builder = ConnectionBuilder::new("/dev/rfcomm0")
+            .baud_rate(9600)
+            .config(ConfigCommand::Raw57600)
+            .config(ConfigCommand::RawOnlyOutput)
+            .config(ConfigCommand::Normal9600);
+        builder.configure(Box::new(MockPort(line.clone()))).unwrap();
+
+        // every command goes out at the rate the module listens at, then the port follows it
+        let line = line.lock().unwrap();
+        assert_eq!(line.written, vec![0x02, 0x10, 0x21, 0x00]);
+        assert_eq!(line.rates, vec![9600, 57_600, 57_600, 57_600]);
+        assert_eq!(line.baud_rate, 9600);
+    }
+
+    #[test]
+    fn test_answer_timeout() {
+        let line = Arc::new(Mutex::new(Line::default()));
+        let builder = ConnectionBuilder::new("/dev/rfcomm0").timeout(Duration::from_millis(50));
+        let mut dongle = builder.dongle(MockPort(line));
+        let start = Instant::now();
+        assert!(matches!(dongle.disconnect(), Err(Error::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
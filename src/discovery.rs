@@ -0,0 +1,114 @@
+use crate::{Error, Parser};
+use log::{debug, info};
+use serialport::{SerialPortType, UsbPortInfo};
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+// USB IDs of the MindWave dongle's Silicon Labs CP210x USB to UART bridge
+pub const DONGLE_VID: u16 = 0x10c4;
+pub const DONGLE_PID: u16 = 0xea60;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DongleInfo {
+    pub path: String,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl DongleInfo {
+    fn new(path: String, usb: UsbPortInfo) -> DongleInfo {
+        DongleInfo {
+            path,
+            serial_number: usb.serial_number,
+            manufacturer: usb.manufacturer,
+            product: usb.product,
+        }
+    }
+}
+
+// Serial ports whose USB IDs match the dongle's. Other adapters using the same bridge chip
+// are listed too; probe them to tell them apart.
+pub fn list_dongles() -> Result<Vec<DongleInfo>, Error> {
+    let dongles = serialport::available_ports()?
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            SerialPortType::UsbPort(usb) if usb.vid == DONGLE_VID && usb.pid == DONGLE_PID => {
+                Some(DongleInfo::new(port.port_name, usb))
+            }
+            _ => None,
+        })
+        .collect();
+    debug!("dongles found: {:?}", dongles);
+    Ok(dongles)
+}
+
+// Reads from `port` until it sends a valid ThinkGear packet or `timeout` passes. A dongle sends
+// at least one packet a second, even in standby.
+pub fn probe<R: Read + ?Sized>(port: &mut R, timeout: Duration) -> Result<bool, Error> {
+    let deadline = Instant::now() + timeout;
+    let mut parser = Parser::new();
+    let mut buf = [0; 256];
+    while Instant::now() < deadline {
+        match port.read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(n) => {
+                if buf[..n]
+                    .iter()
+                    .any(|&b| matches!(parser.parse(b), Ok(Some(_))))
+                {
+                    return Ok(true);
+                }
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::Io(e)),
+        }
+    }
+    Ok(false)
+}
+
+// Opens the serial port at `path` and probes it for ThinkGear packets
+pub fn probe_dongle(path: &str) -> Result<bool, Error> {
+    let mut port = serialport::new(path, 115_200)
+        .timeout(Duration::from_millis(100))
+        .open()?;
+    probe(&mut port, Duration::from_millis(1500))
+}
+
+// Path of the first dongle found, checking that it sends ThinkGear packets when `probe` is set
+pub fn find_dongle(probe: bool) -> Result<String, Error> {
+    for dongle in list_dongles()? {
+        if !probe {
+            return Ok(dongle.path);
+        }
+        match probe_dongle(&dongle.path) {
+            Ok(true) => {
+                info!("found dongle at {}", dongle.path);
+                return Ok(dongle.path);
+            }
+            Ok(false) => debug!("no ThinkGear packets from {}", dongle.path),
+            Err(e) => debug!("cannot probe {}: {}", dongle.path, e),
+        }
+    }
+    Err(Error::NoDongle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::SimTransport;
+    use crate::Scenario;
+
+    #[test]
+    fn test_probe() {
+        let mut dongle = SimTransport::new(0xa05f, Scenario::Normal);
+        assert!(probe(&mut dongle, Duration::from_secs(1)).unwrap());
+
+        // sync bytes alone do not make a dongle
+        let mut other: &[u8] = &[0x00, 0xaa, 0xaa, 0x02, 0x04, 0x0d, 0x00, 0x31, 0x32];
+        assert!(!probe(&mut other, Duration::from_secs(1)).unwrap());
+    }
+}
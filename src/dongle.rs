@@ -0,0 +1,247 @@
+use crate::{Error, PacketType, Parser, Transport};
+use log::{debug, info};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+const CONNECT: u8 = 0xc0;
+const DISCONNECT: u8 = 0xc1;
+const AUTO_CONNECT: u8 = 0xc2;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DongleStatus {
+    Connected(u16),
+    // Headset with this ID not found, or no headset found during auto-connect
+    NotFound(Option<u16>),
+    Disconnected(u16),
+    // The last command was denied
+    Denied,
+    Standby,
+    Scanning,
+}
+
+impl DongleStatus {
+    pub fn from_row(row: &PacketType) -> Option<DongleStatus> {
+        match row {
+            PacketType::HeadsetConnected(id) => Some(DongleStatus::Connected(*id)),
+            PacketType::HeadsetNotFound(id) => Some(DongleStatus::NotFound(Some(*id))),
+            PacketType::NoHeadsetFound => Some(DongleStatus::NotFound(None)),
+            PacketType::HeadsetDisconnected(id) => Some(DongleStatus::Disconnected(*id)),
+            PacketType::RequestDenied => Some(DongleStatus::Denied),
+            PacketType::Standby => Some(DongleStatus::Standby),
+            PacketType::FindHeadset => Some(DongleStatus::Scanning),
+            _ => None,
+        }
+    }
+}
+
+// Drives the dongle commands over a transport and waits for the dongle's answers. Bytes read
+// past an answer are kept, so reading from the dongle afterwards continues the stream where
+// the handshake stopped.
+pub struct Dongle<T> {
+    transport: T,
+    parser: Parser,
+    timeout: Duration,
+    status: Option<DongleStatus>,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+}
+
+impl<T: Transport> Dongle<T> {
+    pub fn new(transport: T) -> Dongle<T> {
+        Dongle {
+            transport,
+            parser: Parser::new(),
+            timeout: Duration::from_secs(10),
+            status: None,
+            buf: vec![0; 2048],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    // Sets how long to wait for the dongle to answer a command
+    pub fn timeout(mut self, timeout: Duration) -> Dongle<T> {
+        self.timeout = timeout;
+        self
+    }
+
+    // Last status reported by the dongle
+    pub fn status(&self) -> Option<DongleStatus> {
+        self.status
+    }
+
+    pub fn connect(&mut self, id: u16) -> Result<DongleStatus, Error> {
+        info!("connecting to headset {:#06x}", id);
+        let [hi, lo] = id.to_be_bytes();
+        self.command(&[CONNECT, hi, lo])?;
+        self.wait(|s| {
+            matches!(
+                s,
+                DongleStatus::Connected(_) | DongleStatus::NotFound(_) | DongleStatus::Denied
+            )
+        })
+    }
+
+    pub fn auto_connect(&mut self) -> Result<DongleStatus, Error> {
+        info!("connecting to any headset");
+        self.command(&[AUTO_CONNECT])?;
+        self.wait(|s| {
+            matches!(
+                s,
+                DongleStatus::Connected(_) | DongleStatus::NotFound(_) | DongleStatus::Denied
+            )
+        })
+    }
+
+    pub fn disconnect(&mut self) -> Result<DongleStatus, Error> {
+        info!("disconnecting");
+        self.command(&[DISCONNECT])?;
+        self.wait(|s| {
+            matches!(
+                s,
+                DongleStatus::Disconnected(_) | DongleStatus::Denied | DongleStatus::Standby
+            )
+        })
+    }
+
+    // Connects to the headset with the given two byte ID, or to any headset when given
+    // HEADSETID_AUTOCONNECT
+    pub fn connect_headset(&mut self, headset: &[u8]) -> Result<DongleStatus, Error> {
+        match headset {
+            [hi, lo] => self.connect(((*hi as u16) << 8) | (*lo as u16)),
+            [AUTO_CONNECT] => self.auto_connect(),
+            _ => Err(Error::InvalidHeadsetId(headset.len())),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    fn command(&mut self, command: &[u8]) -> Result<(), Error> {
+        self.transport.write_all(command)?;
+        self.transport.flush()?;
+        Ok(())
+    }
+
+    fn wait<F: Fn(&DongleStatus) -> bool>(&mut self, done: F) -> Result<DongleStatus, Error> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            while self.pos < self.len {
+                let data = self.buf[self.pos];
+                self.pos += 1;
+                if let Ok(Some(packet)) = self.parser.parse(data) {
+                    for row in packet.rows.iter() {
+                        if let Some(status) = DongleStatus::from_row(row) {
+                            debug!("dongle status {:?}", status);
+                            self.status = Some(status);
+                            if done(&status) {
+                                return Ok(status);
+                            }
+                        }
+                    }
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+            match self.transport.read(&mut self.buf) {
+                Ok(0) => return Err(Error::Closed),
+                Ok(n) => {
+                    self.pos = 0;
+                    self.len = n;
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::TimedOut
+                        || e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+    }
+}
+
+impl<T: Read> Read for Dongle<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.len {
+            let n = buf.len().min(self.len - self.pos);
+            buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        self.transport.read(buf)
+    }
+}
+
+impl<T: Write> Write for Dongle<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transport.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::SimTransport;
+    use crate::Scenario;
+    use pretty_assertions::assert_eq;
+
+    fn sim_dongle(scenario: Scenario) -> Dongle<SimTransport> {
+        Dongle::new(SimTransport::new(0xa05f, scenario))
+    }
+
+    #[test]
+    fn test_dongle_handshake() {
+        let mut dongle = sim_dongle(Scenario::Normal);
+        assert_eq!(dongle.disconnect().unwrap(), DongleStatus::Denied);
+        assert_eq!(
+            dongle.connect(0x1234).unwrap(),
+            DongleStatus::NotFound(Some(0x1234))
+        );
+        assert_eq!(
+            dongle.connect(0xa05f).unwrap(),
+            DongleStatus::Connected(0xa05f)
+        );
+        assert_eq!(dongle.status(), Some(DongleStatus::Connected(0xa05f)));
+        assert!(dongle.get_ref().sim.is_connected());
+
+        // the data stream continues after the handshake
+        let mut buf = [0; 256];
+        let n = dongle.read(&mut buf).unwrap();
+        let mut parser = Parser::new();
+        let rows: Vec<_> = parser
+            .parse_slice(&buf[..n])
+            .filter_map(Result::ok)
+            .collect();
+        assert!(matches!(rows[0], PacketType::RawValue(_)));
+
+        assert_eq!(dongle.auto_connect().unwrap(), DongleStatus::Denied);
+        assert_eq!(
+            dongle.disconnect().unwrap(),
+            DongleStatus::Disconnected(0xa05f)
+        );
+        assert_eq!(
+            dongle.connect_headset(&[0xc2]).unwrap(),
+            DongleStatus::Connected(0xa05f)
+        );
+
+        let mut dongle = sim_dongle(Scenario::HeadsetNotFound);
+        assert_eq!(dongle.auto_connect().unwrap(), DongleStatus::NotFound(None));
+        assert!(matches!(
+            dongle.connect_headset(&[0xa0, 0x5f, 0x00]),
+            Err(Error::InvalidHeadsetId(3))
+        ));
+
+        let mut dongle = Dongle::new(crate::ReadOnly(&[][..]));
+        assert!(matches!(dongle.disconnect(), Err(Error::Closed)));
+    }
+}
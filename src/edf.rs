@@ -0,0 +1,1058 @@
+use crate::{AsicEeg, EegPower, Event, PacketType, SessionEvent, RAW_SAMPLE_RATE};
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Every data record holds one second of the raw wave
+const RECORD_SAMPLES: usize = RAW_SAMPLE_RATE as usize;
+// Size of the annotation signal in samples per data record
+const ANNOTATION_SAMPLES: usize = 64;
+// Longest annotation text kept, in bytes, so that any annotation fits in one data record
+const MAX_ANNOTATION: usize = 80;
+// Offsets of the reserved field, telling continuous from discontinuous recordings, and of the
+// number of data records in the header
+const RESERVED_OFFSET: u64 = 192;
+const RECORDS_OFFSET: u64 = 236;
+// How late a raw sample may arrive behind its place in the recording before the recording
+// resumes at the sample's real time, leaving a gap
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+const BANDS: [&str; 8] = [
+    "Delta",
+    "Theta",
+    "Low Alpha",
+    "High Alpha",
+    "Low Beta",
+    "High Beta",
+    "Low Gamma",
+    "Mid Gamma",
+];
+
+// European Data Format flavour: EDF+ stores 16-bit samples, BDF+ 24-bit samples, which keeps
+// the band powers exact
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EdfFormat {
+    Edf,
+    Bdf,
+}
+
+impl EdfFormat {
+    fn sample_size(self) -> usize {
+        match self {
+            EdfFormat::Edf => 2,
+            EdfFormat::Bdf => 3,
+        }
+    }
+
+    fn digital_range(self) -> (i32, i32) {
+        match self {
+            EdfFormat::Edf => (-32768, 32767),
+            EdfFormat::Bdf => (-8_388_608, 8_388_607),
+        }
+    }
+
+    fn annotation_label(self) -> &'static str {
+        match self {
+            EdfFormat::Edf => "EDF Annotations",
+            EdfFormat::Bdf => "BDF Annotations",
+        }
+    }
+
+    fn reserved(self, continuous: bool) -> &'static str {
+        match (self, continuous) {
+            (EdfFormat::Edf, true) => "EDF+C",
+            (EdfFormat::Edf, false) => "EDF+D",
+            (EdfFormat::Bdf, true) => "BDF+C",
+            (EdfFormat::Bdf, false) => "BDF+D",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct EdfSignal {
+    pub label: String,
+    pub transducer: String,
+    pub physical_dimension: String,
+    pub physical_min: f64,
+    pub physical_max: f64,
+    pub digital_min: i32,
+    pub digital_max: i32,
+    pub prefiltering: String,
+    pub samples_per_record: usize,
+}
+
+impl EdfSignal {
+    fn new(label: &str, dimension: &str, physical: (f64, f64), digital: (i32, i32)) -> EdfSignal {
+        EdfSignal {
+            label: label.to_string(),
+            transducer: String::new(),
+            physical_dimension: dimension.to_string(),
+            physical_min: physical.0,
+            physical_max: physical.1,
+            digital_min: digital.0,
+            digital_max: digital.1,
+            prefiltering: String::new(),
+            samples_per_record: 1,
+        }
+    }
+
+    fn scale(&self) -> f64 {
+        (self.physical_max - self.physical_min) / (self.digital_max - self.digital_min) as f64
+    }
+
+    pub fn to_physical(&self, digital: i32) -> f64 {
+        (digital - self.digital_min) as f64 * self.scale() + self.physical_min
+    }
+
+    pub fn to_digital(&self, physical: f64) -> i32 {
+        let digital = ((physical - self.physical_min) / self.scale()).round() as i64
+            + self.digital_min as i64;
+        digital.clamp(self.digital_min as i64, self.digital_max as i64) as i32
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Annotation {
+    // Seconds since the start of the recording
+    pub onset: f64,
+    pub duration: Option<f64>,
+    pub text: String,
+}
+
+// Signals written by EdfWriter, in order: the raw wave, the eight band powers, attention,
+// meditation and signal quality, followed by the annotation signal
+fn signals(format: EdfFormat) -> Vec<EdfSignal> {
+    // a raw value of 1 is 1.8 V / 4096 / 2000 of gain, the whole i16 range at most
+    let mut raw = EdfSignal::new("EEG Raw", "uV", (-7200.0, 7199.78), (-32768, 32767));
+    raw.transducer = "MindWave dry electrode".to_string();
+    raw.samples_per_record = RECORD_SAMPLES;
+    let mut signals = vec![raw];
+    for band in BANDS.iter() {
+        signals.push(EdfSignal::new(
+            band,
+            "",
+            (0.0, 16_777_215.0),
+            format.digital_range(),
+        ));
+    }
+    signals.push(EdfSignal::new("Attention", "%", (0.0, 100.0), (0, 100)));
+    signals.push(EdfSignal::new("Meditation", "%", (0.0, 100.0), (0, 100)));
+    signals.push(EdfSignal::new("Poor Signal", "", (0.0, 200.0), (0, 200)));
+    let mut annotations = EdfSignal::new(
+        format.annotation_label(),
+        "",
+        (-1.0, 1.0),
+        format.digital_range(),
+    );
+    annotations.samples_per_record = ANNOTATION_SAMPLES;
+    signals.push(annotations);
+    signals
+}
+
+// Writes a session to an EDF+ or BDF+ file as it is read. The raw wave drives the clock: a
+// data record is written for every second of raw samples, with the latest band powers, eSense
+// and signal quality values, and the blinks, session events and markers seen meanwhile as
+// annotations. Without raw wave output no data record is ever completed.
+//
+// The recording starts with the first raw sample. When the raw wave stops, as while the
+// session reconnects, the unfinished data record is padded and the recording resumes at the
+// time the next sample was read, which turns the file into a discontinuous EDF+D or BDF+D
+// file with the onset of every data record in its annotations. Times are those of the session:
+// the times events were read, scaled by the speed a recording is replayed at.
+pub struct EdfWriter<W> {
+    writer: W,
+    format: EdfFormat,
+    signals: Vec<EdfSignal>,
+    patient: String,
+    start: Option<SystemTime>,
+    // seconds of the session per second of reading it, None without a clock
+    speed: Option<f64>,
+    // an instant and the wall clock time of the session at it
+    origin: Option<(Instant, SystemTime)>,
+    // when the first raw sample was read
+    first: Option<Instant>,
+    header_written: bool,
+    continuous: bool,
+    records: u64,
+    // seconds from the first raw sample to the first sample of the current data record
+    onset: f64,
+    raw: Vec<i32>,
+    // latest value of each of the once per record signals
+    held: Vec<f64>,
+    // time and text of the annotations waiting for a data record, in seconds since the first
+    // raw sample
+    annotations: VecDeque<(f64, String)>,
+}
+
+impl<W: Write + Seek> EdfWriter<W> {
+    pub fn new(writer: W, format: EdfFormat) -> EdfWriter<W> {
+        let signals = signals(format);
+        EdfWriter {
+            writer,
+            format,
+            held: vec![0.0; signals.len() - 2],
+            signals,
+            patient: "X X X X".to_string(),
+            start: None,
+            speed: Some(1.0),
+            origin: None,
+            first: None,
+            header_written: false,
+            continuous: true,
+            records: 0,
+            onset: 0.0,
+            raw: Vec::with_capacity(RECORD_SAMPLES),
+            annotations: VecDeque::new(),
+        }
+    }
+
+    // Sets the patient code of the EDF+ patient identification
+    pub fn patient(mut self, code: &str) -> EdfWriter<W> {
+        self.patient = format!("{} X X X", edf_plus_field(code));
+        self
+    }
+
+    // Sets the wall clock time of the first raw sample, by default the time it was read
+    pub fn start(mut self, start: SystemTime) -> EdfWriter<W> {
+        self.start = Some(start);
+        self
+    }
+
+    // Sets how many seconds of the session pass per second of reading it, such as the speed a
+    // capture is replayed at. None, for sources read without a clock, places the raw samples
+    // back to back and markers at the latest sample.
+    pub fn speed(mut self, speed: Option<f64>) -> EdfWriter<W> {
+        self.speed = speed;
+        self
+    }
+
+    // Sets the wall clock time of the session at the instant `at`, such as the time a capture
+    // started at the instant its replay did. The recording then starts at the session's time
+    // of the first raw sample instead of the time it was read.
+    pub fn origin(mut self, at: Instant, time: SystemTime) -> EdfWriter<W> {
+        self.origin = Some((at, time));
+        self
+    }
+
+    pub fn signals(&self) -> &[EdfSignal] {
+        &self.signals
+    }
+
+    // Adds a session event to the recording. Rows are placed at the time they were read,
+    // session events at the time they are recorded.
+    pub fn record(&mut self, event: &Event) -> io::Result<()> {
+        match event {
+            Event::Row(PacketType::RawValue(value), info) => self.sample(*value, info.received)?,
+            Event::Row(PacketType::AsicEeg(eeg), _) => self.held[..8].copy_from_slice(&bands(eeg)),
+            Event::Row(PacketType::EegPower(eeg), _) => {
+                self.held[..8].copy_from_slice(&float_bands(eeg))
+            }
+            Event::Row(PacketType::Attention(value), _) => self.held[8] = *value as f64,
+            Event::Row(PacketType::Meditation(value), _) => self.held[9] = *value as f64,
+            Event::Row(PacketType::PoorSignal(value), _) => self.held[10] = *value as f64,
+            Event::Row(PacketType::Blink(value), info) => {
+                self.annotate_at(&format!("Blink {}", value), info.received)
+            }
+            Event::Session(event) => self.annotate(&session_text(event)),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Adds a marker at the current time
+    pub fn annotate(&mut self, text: &str) {
+        self.annotate_at(text, Instant::now());
+    }
+
+    // Adds a marker at the time `at`. Markers before the first raw sample are placed at the
+    // start of the recording.
+    pub fn annotate_at(&mut self, text: &str, at: Instant) {
+        let mut end = text.len().min(MAX_ANNOTATION);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let onset = match self.speed {
+            Some(_) => self.time(at),
+            None => self.onset + self.raw.len() as f64 / RECORD_SAMPLES as f64,
+        };
+        self.annotations.push_back((
+            onset,
+            text[..end].replace(&['\0', '\u{14}', '\u{15}'][..], " "),
+        ));
+    }
+
+    // Pads and writes the last data record, then writes the number of data records to the
+    // header. Returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        while !self.raw.is_empty() || !self.annotations.is_empty() {
+            // annotations long after the last raw sample get a data record at their own time
+            if let Some((onset, _)) = self.annotations.front() {
+                if self.raw.is_empty() && *onset >= self.onset + 1.0 {
+                    // never before the end of the previous data record
+                    let onset = onset.floor().max(self.onset);
+                    self.resume(onset)?;
+                }
+            }
+            self.raw.resize(RECORD_SAMPLES, 0);
+            self.write_record()?;
+        }
+        if !self.header_written {
+            self.write_header()?;
+        }
+        self.writer.seek(SeekFrom::Start(RECORDS_OFFSET))?;
+        self.writer
+            .write_all(ascii(&self.records.to_string(), 8).as_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    // Seconds from the first raw sample to the instant `at` in the session's time, 0 before
+    // the first sample
+    fn time(&self, at: Instant) -> f64 {
+        match self.first {
+            Some(first) => {
+                at.saturating_duration_since(first).as_secs_f64() * self.speed.unwrap_or(0.0)
+            }
+            None => 0.0,
+        }
+    }
+
+    fn sample(&mut self, value: i16, received: Instant) -> io::Result<()> {
+        if self.first.is_none() {
+            self.first = Some(received);
+            if self.start.is_none() {
+                let speed = self.speed.unwrap_or(0.0);
+                self.start = Some(match self.origin {
+                    Some((at, time)) => {
+                        time + received.saturating_duration_since(at).mul_f64(speed)
+                    }
+                    None => SystemTime::now()
+                        .checked_sub(received.elapsed())
+                        .unwrap_or_else(SystemTime::now),
+                });
+            }
+        } else if self.speed.is_some() {
+            let place = self.onset + self.raw.len() as f64 / RECORD_SAMPLES as f64;
+            let time = self.time(received);
+            if time > place + MAX_DELAY.as_secs_f64() {
+                if !self.raw.is_empty() {
+                    self.raw.resize(RECORD_SAMPLES, 0);
+                    self.write_record()?;
+                }
+                self.resume(time)?;
+            }
+        }
+        self.raw.push(value as i32);
+        if self.raw.len() == RECORD_SAMPLES {
+            self.write_record()?;
+        }
+        Ok(())
+    }
+
+    // Starts the next data record at `onset` instead of right after the previous one, marking
+    // the recording discontinuous
+    fn resume(&mut self, onset: f64) -> io::Result<()> {
+        self.onset = onset;
+        if self.continuous {
+            self.continuous = false;
+            if self.header_written {
+                self.writer.seek(SeekFrom::Start(RESERVED_OFFSET))?;
+                self.writer
+                    .write_all(self.format.reserved(false).as_bytes())?;
+                self.writer.seek(SeekFrom::End(0))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let start = *self.start.get_or_insert_with(SystemTime::now);
+        let (date, time, recording_date) = civil_time(start);
+        let mut header = String::new();
+        header += &ascii(&self.patient, 80);
+        header += &ascii(
+            &format!("Startdate {} X X NeuroSky_MindWave", recording_date),
+            80,
+        );
+        header += &ascii(&date, 8);
+        header += &ascii(&time, 8);
+        header += &ascii(&(256 * (self.signals.len() + 1)).to_string(), 8);
+        header += &ascii(self.format.reserved(self.continuous), 44);
+        // the number of data records is unknown until the recording is finished
+        header += &ascii("-1", 8);
+        header += &ascii("1", 8);
+        header += &ascii(&self.signals.len().to_string(), 4);
+        // each field of the signal headers, for every signal in turn, and its width
+        type Field = (fn(&EdfSignal) -> String, usize);
+        let fields: [Field; 10] = [
+            (|s| s.label.clone(), 16),
+            (|s| s.transducer.clone(), 80),
+            (|s| s.physical_dimension.clone(), 8),
+            (|s| number(s.physical_min), 8),
+            (|s| number(s.physical_max), 8),
+            (|s| s.digital_min.to_string(), 8),
+            (|s| s.digital_max.to_string(), 8),
+            (|s| s.prefiltering.clone(), 80),
+            (|s| s.samples_per_record.to_string(), 8),
+            (|_| String::new(), 32),
+        ];
+        for (field, width) in fields.iter() {
+            for signal in self.signals.iter() {
+                header += &ascii(&field(signal), *width);
+            }
+        }
+        match self.format {
+            EdfFormat::Edf => self.writer.write_all(b"0       ")?,
+            EdfFormat::Bdf => self.writer.write_all(b"\xffBIOSEMI")?,
+        }
+        self.writer.write_all(header.as_bytes())?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    fn write_record(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.write_header()?;
+        }
+        let size = self.format.sample_size();
+        let mut record = Vec::new();
+        for value in self.raw.drain(..) {
+            record.extend_from_slice(&value.to_le_bytes()[..size]);
+        }
+        for (signal, value) in self.signals[1..].iter().zip(self.held.iter()) {
+            record.extend_from_slice(&signal.to_digital(*value).to_le_bytes()[..size]);
+        }
+
+        // the first annotation of a data record keeps the time of the record; annotations
+        // from a gap before it come along with it
+        let capacity = ANNOTATION_SAMPLES * size;
+        let mut tal = format!("+{}\u{14}\u{14}\0", seconds(self.onset)).into_bytes();
+        let end = self.onset + 1.0;
+        while let Some((onset, text)) = self.annotations.front() {
+            if *onset >= end {
+                break;
+            }
+            let annotation = format!("+{}\u{14}{}\u{14}\0", seconds(*onset), text);
+            if tal.len() + annotation.len() > capacity {
+                // left for the next data record, keeping its onset
+                break;
+            }
+            tal.extend_from_slice(annotation.as_bytes());
+            self.annotations.pop_front();
+        }
+        tal.resize(capacity, 0);
+        record.extend_from_slice(&tal);
+
+        self.writer.write_all(&record)?;
+        self.records += 1;
+        self.onset += 1.0;
+        Ok(())
+    }
+}
+
+// An EDF+ or BDF+ file read back in full
+#[derive(PartialEq, Debug, Clone)]
+pub struct EdfFile {
+    pub format: EdfFormat,
+    pub patient: String,
+    pub recording: String,
+    // dd.mm.yy and hh.mm.ss
+    pub start_date: String,
+    pub start_time: String,
+    pub record_duration: f64,
+    // False for EDF+D and BDF+D files, whose data records may leave gaps between them
+    pub continuous: bool,
+    // Onset of every data record in seconds since the start, when the file has annotations
+    pub onsets: Vec<f64>,
+    // Data signals, without the annotation signal
+    pub signals: Vec<EdfSignal>,
+    // Digital samples of each data signal
+    pub samples: Vec<Vec<i32>>,
+    pub annotations: Vec<Annotation>,
+}
+
+impl EdfFile {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<EdfFile> {
+        let mut header = vec![0; 256];
+        reader.read_exact(&mut header)?;
+        let format = match &header[..8] {
+            b"\xffBIOSEMI" => EdfFormat::Bdf,
+            b"0       " => EdfFormat::Edf,
+            _ => return Err(invalid("not an EDF or BDF file")),
+        };
+        let records: i64 = parse(&header[236..244])?;
+        let record_duration: f64 = parse(&header[244..252])?;
+        let count: usize = parse(&header[252..256])?;
+        let mut signal_header = vec![0; 256 * count];
+        reader.read_exact(&mut signal_header)?;
+
+        let mut offset = 0;
+        let mut field = |width: usize| -> Vec<&[u8]> {
+            let start = offset;
+            offset += width * count;
+            (0..count)
+                .map(|i| &signal_header[start + i * width..start + (i + 1) * width])
+                .collect()
+        };
+        let labels = field(16);
+        let transducers = field(80);
+        let dimensions = field(8);
+        let physical_mins = field(8);
+        let physical_maxs = field(8);
+        let digital_mins = field(8);
+        let digital_maxs = field(8);
+        let prefilterings = field(80);
+        let samples_per_record = field(8);
+        let mut signals = Vec::new();
+        for i in 0..count {
+            signals.push(EdfSignal {
+                label: text(labels[i]),
+                transducer: text(transducers[i]),
+                physical_dimension: text(dimensions[i]),
+                physical_min: parse(physical_mins[i])?,
+                physical_max: parse(physical_maxs[i])?,
+                digital_min: parse(digital_mins[i])?,
+                digital_max: parse(digital_maxs[i])?,
+                prefiltering: text(prefilterings[i]),
+                samples_per_record: parse(samples_per_record[i])?,
+            });
+        }
+
+        let size = format.sample_size();
+        let annotation = signals
+            .iter()
+            .position(|s| s.label == format.annotation_label());
+        let mut samples = vec![Vec::new(); count];
+        let mut annotations = Vec::new();
+        let mut onsets = Vec::new();
+        let mut record = vec![0; signals.iter().map(|s| s.samples_per_record * size).sum()];
+        let mut read = 0;
+        while records < 0 || read < records {
+            match reader.read_exact(&mut record) {
+                Ok(()) => (),
+                Err(e) if records < 0 && e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let mut data = &record[..];
+            for (i, signal) in signals.iter().enumerate() {
+                let (values, rest) = data.split_at(signal.samples_per_record * size);
+                data = rest;
+                if Some(i) == annotation {
+                    let (onset, tals) = parse_tals(values)?;
+                    onsets.extend(onset);
+                    annotations.extend(tals);
+                } else {
+                    samples[i].extend(values.chunks(size).map(sample));
+                }
+            }
+            read += 1;
+        }
+        if let Some(i) = annotation {
+            signals.remove(i);
+            samples.remove(i);
+        }
+        Ok(EdfFile {
+            format,
+            patient: text(&header[8..88]),
+            recording: text(&header[88..168]),
+            start_date: text(&header[168..176]),
+            start_time: text(&header[176..184]),
+            record_duration,
+            continuous: !text(&header[192..236]).ends_with("+D"),
+            onsets,
+            signals,
+            samples,
+            annotations,
+        })
+    }
+
+    pub fn signal(&self, label: &str) -> Option<usize> {
+        self.signals.iter().position(|s| s.label == label)
+    }
+
+    // Samples of a signal in its physical dimension
+    pub fn physical(&self, signal: usize) -> Vec<f64> {
+        let spec = &self.signals[signal];
+        self.samples[signal]
+            .iter()
+            .map(|d| spec.to_physical(*d))
+            .collect()
+    }
+
+    // Wall clock time of the start of the recording, read as UTC. The year comes from the
+    // EDF+ start date of the recording field when it has one.
+    pub fn start(&self) -> Option<SystemTime> {
+        let fields = |value: &str| -> Option<Vec<i64>> {
+            let fields: Option<Vec<i64>> = value.split('.').map(|v| v.parse().ok()).collect();
+            fields.filter(|fields| fields.len() == 3)
+        };
+        let date = fields(&self.start_date)?;
+        let time = fields(&self.start_time)?;
+        // two-digit years from 85 on are in the 20th century
+        let year = self
+            .recording
+            .split(' ')
+            .nth(1)
+            .and_then(|date| date.rsplit('-').next())
+            .and_then(|year| year.parse().ok())
+            .unwrap_or(date[2] + if date[2] >= 85 { 1900 } else { 2000 });
+        let days = days_from_civil(year, date[1], date[0]);
+        let secs = days * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+        match secs {
+            secs if secs >= 0 => Some(UNIX_EPOCH + Duration::from_secs(secs as u64)),
+            _ => None,
+        }
+    }
+}
+
+fn bands(eeg: &AsicEeg) -> [f64; 8] {
+    [
+        eeg.delta as f64,
+        eeg.theta as f64,
+        eeg.low_alpha as f64,
+        eeg.high_alpha as f64,
+        eeg.low_beta as f64,
+        eeg.high_beta as f64,
+        eeg.low_gamma as f64,
+        eeg.mid_gamma as f64,
+    ]
+}
+
+fn float_bands(eeg: &EegPower) -> [f64; 8] {
+    [
+        eeg.delta as f64,
+        eeg.theta as f64,
+        eeg.low_alpha as f64,
+        eeg.high_alpha as f64,
+        eeg.low_beta as f64,
+        eeg.high_beta as f64,
+        eeg.low_gamma as f64,
+        eeg.mid_gamma as f64,
+    ]
+}
+
+fn session_text(event: &SessionEvent) -> String {
+    match event {
+        SessionEvent::Connected(id) => format!("Connected {:04x}", id),
+        SessionEvent::Lost(reason) => format!("Lost {:?}", reason),
+        SessionEvent::Reconnected(id) => format!("Reconnected {:04x}", id),
+        SessionEvent::GaveUp => "Gave up".to_string(),
+    }
+}
+
+// Little-endian two's complement sample of 2 or 3 bytes
+fn sample(bytes: &[u8]) -> i32 {
+    let mut value = [0; 4];
+    value[..bytes.len()].copy_from_slice(bytes);
+    let shift = 32 - 8 * bytes.len();
+    (i32::from_le_bytes(value) << shift) >> shift
+}
+
+// Onset of one data record, kept by its first time-stamped annotation list, and the
+// annotations of the lists. The annotations without text only keep time and are skipped.
+fn parse_tals(bytes: &[u8]) -> io::Result<(Option<f64>, Vec<Annotation>)> {
+    let mut record_onset = None;
+    let mut annotations = Vec::new();
+    for tal in bytes.split(|b| *b == 0).filter(|tal| !tal.is_empty()) {
+        let tal = std::str::from_utf8(tal).map_err(|_| invalid("annotation is not UTF-8"))?;
+        let mut parts = tal.split('\u{14}');
+        let time = parts.next().unwrap_or_default();
+        let mut time = time.split('\u{15}');
+        let onset = time.next().unwrap_or_default();
+        let onset = onset
+            .parse()
+            .map_err(|_| invalid("invalid annotation onset"))?;
+        let duration = match time.next() {
+            Some(d) => Some(
+                d.parse()
+                    .map_err(|_| invalid("invalid annotation duration"))?,
+            ),
+            None => None,
+        };
+        record_onset.get_or_insert(onset);
+        for text in parts.filter(|t| !t.is_empty()) {
+            annotations.push(Annotation {
+                onset,
+                duration,
+                text: text.to_string(),
+            });
+        }
+    }
+    Ok((record_onset, annotations))
+}
+
+// EDF+ subfields are separated by spaces, so spaces inside one are replaced
+fn edf_plus_field(value: &str) -> String {
+    value.replace(' ', "_")
+}
+
+// Left aligned, space padded and cut to `width`, with anything but printable ASCII replaced
+fn ascii(value: &str, width: usize) -> String {
+    let mut field: String = value
+        .chars()
+        .map(|c| if (' '..='~').contains(&c) { c } else { '_' })
+        .take(width)
+        .collect();
+    field.extend(std::iter::repeat(' ').take(width - field.len()));
+    field
+}
+
+// Number written with as many decimals as fit in a header field
+fn number(value: f64) -> String {
+    (0..=3)
+        .rev()
+        .map(|decimals| seconds_with(value, decimals))
+        .find(|s| s.len() <= 8)
+        .unwrap_or_else(|| format!("{:.0}", value))
+}
+
+fn seconds(value: f64) -> String {
+    seconds_with(value, 4)
+}
+
+// Number with at most `decimals` decimals and no trailing zeros
+fn seconds_with(value: f64, decimals: usize) -> String {
+    let s = format!("{:.*}", decimals, value);
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        s
+    }
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end().to_string()
+}
+
+fn parse<T: std::str::FromStr>(bytes: &[u8]) -> io::Result<T> {
+    text(bytes)
+        .trim()
+        .parse()
+        .map_err(|_| invalid("invalid number in header"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Header start date dd.mm.yy, start time hh.mm.ss and EDF+ start date dd-MMM-yyyy, in UTC
+fn civil_time(time: SystemTime) -> (String, String, String) {
+    const MONTHS: [&str; 12] = [
+        "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+    ];
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // days since 1970-01-01 to a proleptic Gregorian date
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        format!("{:02}.{:02}.{:02}", day, month, year % 100),
+        format!("{:02}.{:02}.{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+        format!("{:02}-{}-{}", day, MONTHS[month as usize - 1], year),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PacketInfo;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+    use std::time::{Duration, Instant};
+
+    fn row(row: PacketType, received: Instant) -> Event {
+        Event::Row(
+            row,
+            PacketInfo {
+                sequence: 0,
+                received,
+            },
+        )
+    }
+
+    // Time the `i`th raw sample is read at, `start` being the first
+    fn at(start: Instant, i: u32) -> Instant {
+        start + Duration::from_secs(1) * i / RECORD_SAMPLES as u32
+    }
+
+    fn session(writer: &mut EdfWriter<Cursor<Vec<u8>>>) -> Vec<i16> {
+        let start = Instant::now();
+        let row = |r, i| row(r, at(start, i));
+        let mut raw = Vec::new();
+        writer
+            .record(&Event::Session(SessionEvent::Connected(0xa05f)))
+            .unwrap();
+        for i in 0..1100 {
+            let value = (i as i32 * 97) % 65536 - 32768;
+            raw.push(value as i16);
+            writer
+                .record(&row(PacketType::RawValue(value as i16), i))
+                .unwrap();
+            match i {
+                100 => {
+                    let eeg = AsicEeg {
+                        delta: 16_777_215,
+                        theta: 1_234_567,
+                        low_alpha: 7,
+                        ..AsicEeg::new()
+                    };
+                    writer.record(&row(PacketType::AsicEeg(eeg), i)).unwrap();
+                    writer.record(&row(PacketType::Attention(40), i)).unwrap();
+                    writer.record(&row(PacketType::PoorSignal(200), i)).unwrap();
+                }
+                256 => writer.record(&row(PacketType::Blink(57), i)).unwrap(),
+                600 => writer.annotate_at("eyes closed", at(start, i)),
+                1050 => writer.annotate_at("eye blink task", at(start, i)),
+                _ => (),
+            }
+        }
+        raw
+    }
+
+    #[test]
+    fn test_edf_round_trip() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_792_281_600 + 3723);
+        let mut writer = EdfWriter::new(Cursor::new(Vec::new()), EdfFormat::Edf)
+            .patient("P 01")
+            .start(start);
+        let mut raw = session(&mut writer);
+        let file = writer.finish().unwrap().into_inner();
+        assert_eq!(file.len(), 256 * 14 + 3 * (512 * 2 + 11 * 2 + 64 * 2));
+
+        let edf = EdfFile::read(&file[..]).unwrap();
+        assert_eq!(edf.format, EdfFormat::Edf);
+        assert_eq!(edf.patient, "P_01 X X X");
+        assert_eq!(edf.recording, "Startdate 18-OCT-2026 X X NeuroSky_MindWave");
+        assert_eq!(
+            (&edf.start_date[..], &edf.start_time[..]),
+            ("18.10.26", "01.02.03")
+        );
+        assert_eq!(edf.start(), Some(start));
+        assert_eq!(edf.record_duration, 1.0);
+        assert!(edf.continuous);
+        assert_eq!(edf.onsets, vec![0.0, 1.0, 2.0]);
+        assert_eq!(edf.signals.len(), 12);
+
+        let signal = edf.signal("EEG Raw").unwrap();
+        raw.resize(3 * 512, 0);
+        let digital: Vec<i16> = edf.samples[signal].iter().map(|v| *v as i16).collect();
+        assert_eq!(digital, raw);
+        assert!((edf.physical(signal)[1] - (97.0 - 32768.0) * 0.2197265625).abs() < 0.01);
+
+        // band powers are held from one record to the next, within the 16-bit resolution
+        let delta = edf.physical(edf.signal("Delta").unwrap());
+        assert_eq!(delta.len(), 3);
+        assert!(delta.iter().all(|v| (v - 16_777_215.0).abs() < 256.0));
+        let theta = edf.physical(edf.signal("Theta").unwrap());
+        assert!((theta[2] - 1_234_567.0).abs() < 256.0);
+        assert_eq!(
+            edf.physical(edf.signal("Attention").unwrap()),
+            vec![40.0; 3]
+        );
+        assert_eq!(
+            edf.samples[edf.signal("Poor Signal").unwrap()],
+            vec![200; 3]
+        );
+
+        let annotations: Vec<_> = edf
+            .annotations
+            .iter()
+            .map(|a| (a.onset, a.text.as_str()))
+            .collect();
+        assert_eq!(
+            annotations,
+            vec![
+                (0.0, "Connected a05f"),
+                (0.5, "Blink 57"),
+                (1.1719, "eyes closed"),
+                (2.0508, "eye blink task"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_edf_gap() {
+        // the session was read a while ago, the first sample at `start`
+        let start = Instant::now() - Duration::from_secs(60);
+        let mut writer = EdfWriter::new(Cursor::new(Vec::new()), EdfFormat::Edf);
+        for i in 0..700 {
+            let event = row(PacketType::RawValue(1), at(start, i));
+            writer.record(&event).unwrap();
+        }
+        // the recording starts when the first sample was read, not when it was recorded
+        let secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        let recorded = secs(SystemTime::now() - Duration::from_secs(60));
+        assert!((secs(writer.start.unwrap()) - recorded).abs() < 1.0);
+
+        // the raw wave stops for three seconds while the session reconnects
+        let gap = Duration::from_secs(3);
+        writer.annotate_at("Lost Disconnected", at(start, 700) + gap / 2);
+        for i in 700..1300 {
+            let event = row(PacketType::RawValue(2), at(start, i) + gap);
+            writer.record(&event).unwrap();
+        }
+        writer.annotate_at("Gave up", at(start, 1300) + gap * 10);
+        let file = writer.finish().unwrap().into_inner();
+        assert_eq!(&file[192..197], b"EDF+D");
+
+        let edf = EdfFile::read(&file[..]).unwrap();
+        assert!(!edf.continuous);
+        // the unfinished record before the gap is padded, and the recording resumes at the
+        // time the raw wave came back
+        assert_eq!(edf.onsets, vec![0.0, 1.0, 4.3672, 5.3672, 32.0]);
+        let raw = &edf.samples[edf.signal("EEG Raw").unwrap()];
+        assert_eq!(raw[..1024].iter().filter(|v| **v == 1).count(), 700);
+        assert_eq!(raw[1024..1024 + 600], vec![2; 600][..]);
+        let annotations: Vec<_> = edf
+            .annotations
+            .iter()
+            .map(|a| (a.onset, a.text.as_str()))
+            .collect();
+        assert_eq!(
+            annotations,
+            vec![(2.8672, "Lost Disconnected"), (32.5391, "Gave up")]
+        );
+    }
+
+    #[test]
+    fn test_edf_replay() {
+        // a capture started at `started` replayed four times faster than it was read, the
+        // first raw sample two seconds into the capture
+        let speed = 4.0;
+        let origin = Instant::now() - Duration::from_secs(60);
+        let started = UNIX_EPOCH + Duration::from_secs(1_792_281_600 + 3723);
+        let start = origin + Duration::from_millis(500);
+        let replayed = |i: u32| start + (at(start, i) - start).div_f64(speed);
+        let mut writer = EdfWriter::new(Cursor::new(Vec::new()), EdfFormat::Edf)
+            .speed(Some(speed))
+            .origin(origin, started);
+        for i in 0..700 {
+            let event = row(PacketType::RawValue(1), replayed(i));
+            writer.record(&event).unwrap();
+        }
+        writer.annotate_at("eyes closed", replayed(600));
+
+        // the three seconds the raw wave stopped for pass four times faster too
+        let gap = Duration::from_secs(3).div_f64(speed);
+        writer.annotate_at("Lost Disconnected", replayed(700) + gap / 2);
+        for i in 700..1300 {
+            let event = row(PacketType::RawValue(2), replayed(i) + gap);
+            writer.record(&event).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+
+        let edf = EdfFile::read(&file[..]).unwrap();
+        assert_eq!(edf.start(), Some(started + Duration::from_secs(2)));
+        assert_eq!(edf.onsets, vec![0.0, 1.0, 4.3672, 5.3672]);
+        let annotations: Vec<_> = edf
+            .annotations
+            .iter()
+            .map(|a| (a.onset, a.text.as_str()))
+            .collect();
+        assert_eq!(
+            annotations,
+            vec![(1.1719, "eyes closed"), (2.8672, "Lost Disconnected")]
+        );
+    }
+
+    #[test]
+    fn test_edf_late_annotation() {
+        // a gap leaves the data records at fractional onsets, and an annotation comes after
+        // the last of them ended
+        let start = Instant::now();
+        let mut writer = EdfWriter::new(Cursor::new(Vec::new()), EdfFormat::Edf);
+        for i in 0..700 {
+            let event = row(PacketType::RawValue(1), at(start, i));
+            writer.record(&event).unwrap();
+        }
+        for i in 700..1300 {
+            let event = row(
+                PacketType::RawValue(2),
+                at(start, i) + Duration::from_secs(3),
+            );
+            writer.record(&event).unwrap();
+        }
+        writer.annotate_at("late", start + Duration::from_millis(7400));
+        let file = writer.finish().unwrap().into_inner();
+
+        let edf = EdfFile::read(&file[..]).unwrap();
+        assert_eq!(edf.onsets, vec![0.0, 1.0, 4.3672, 5.3672, 7.0]);
+        assert!(edf.onsets.windows(2).all(|w| w[1] >= w[0] + 1.0));
+        assert_eq!(edf.annotations[0].onset, 7.4);
+    }
+
+    #[test]
+    fn test_edf_float_band_power() {
+        let start = Instant::now();
+        let mut writer = EdfWriter::new(Cursor::new(Vec::new()), EdfFormat::Bdf);
+        let eeg = EegPower {
+            delta: 1500.0,
+            mid_gamma: 2.0,
+            ..EegPower::new()
+        };
+        writer
+            .record(&row(PacketType::EegPower(eeg), start))
+            .unwrap();
+        for i in 0..512 {
+            writer
+                .record(&row(PacketType::RawValue(0), at(start, i)))
+                .unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+
+        let bdf = EdfFile::read(&file[..]).unwrap();
+        assert_eq!(bdf.physical(bdf.signal("Delta").unwrap()), vec![1500.0]);
+        assert_eq!(bdf.physical(bdf.signal("Mid Gamma").unwrap()), vec![2.0]);
+    }
+
+    #[test]
+    fn test_bdf_round_trip() {
+        let mut writer = EdfWriter::new(Cursor::new(Vec::new()), EdfFormat::Bdf);
+        let raw = session(&mut writer);
+        let file = writer.finish().unwrap().into_inner();
+        assert_eq!(&file[..8], b"\xffBIOSEMI");
+
+        let bdf = EdfFile::read(&file[..]).unwrap();
+        assert_eq!(bdf.format, EdfFormat::Bdf);
+        let digital = &bdf.samples[bdf.signal("EEG Raw").unwrap()];
+        assert_eq!(
+            digital[..1100]
+                .iter()
+                .map(|v| *v as i16)
+                .collect::<Vec<_>>(),
+            raw
+        );
+        // 24-bit samples keep the band powers exact
+        assert_eq!(bdf.physical(bdf.signal("Delta").unwrap())[1], 16_777_215.0);
+        assert_eq!(bdf.physical(bdf.signal("Theta").unwrap())[1], 1_234_567.0);
+        assert_eq!(bdf.physical(bdf.signal("Low Alpha").unwrap())[1], 7.0);
+        assert_eq!(bdf.annotations.len(), 4);
+
+        // an unfinished recording is read up to its last complete data record
+        let mut unfinished = file.clone();
+        unfinished[236..244].copy_from_slice(b"-1      ");
+        unfinished.truncate(file.len() - 10);
+        let bdf = EdfFile::read(&unfinished[..]).unwrap();
+        assert_eq!(bdf.samples[0].len(), 2 * 512);
+
+        assert!(EdfFile::read(&b"not an EDF file"[..]).is_err());
+    }
+}
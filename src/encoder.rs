@@ -0,0 +1,265 @@
+use crate::{AsicEeg, EegPower, PacketType};
+use std::fmt;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EncodeError {
+    // Payload longer than the 170 bytes a packet can carry
+    PayloadTooLong(usize),
+    // Value length that the data row with this code cannot carry
+    BadRowLength(u8, usize),
+    // Value that does not fit into the bytes the protocol reserves for it
+    ValueOutOfRange,
+    // Row that carries no data to encode, such as the undefined variants
+    Unencodable,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::PayloadTooLong(len) => {
+                write!(f, "payload of {} bytes larger than 170", len)
+            }
+            EncodeError::BadRowLength(code, len) => write!(
+                f,
+                "data row {:#04x} cannot carry a value of {} bytes",
+                code, len
+            ),
+            EncodeError::ValueOutOfRange => write!(f, "value out of range"),
+            EncodeError::Unencodable => write!(f, "row has no data to encode"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+// Frames a payload as `0xAA 0xAA plength payload checksum`
+pub fn frame(payload: &[u8]) -> Result<Vec<u8>, EncodeError> {
+    if payload.len() > 170 {
+        return Err(EncodeError::PayloadTooLong(payload.len()));
+    }
+    let mut packet = Vec::with_capacity(payload.len() + 4);
+    packet.extend_from_slice(&[0xaa, 0xaa, payload.len() as u8]);
+    packet.extend_from_slice(payload);
+    packet.push(checksum(payload));
+    Ok(packet)
+}
+
+// 1's complement inverse of the 8-bit payload sum
+pub fn checksum(payload: &[u8]) -> u8 {
+    !payload.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+pub fn encode_packet(rows: &[PacketType]) -> Result<Vec<u8>, EncodeError> {
+    let mut payload = Vec::new();
+    for row in rows {
+        encode_row(row, &mut payload)?;
+    }
+    frame(&payload)
+}
+
+// Appends the payload bytes of a decoded row
+pub fn encode_row(row: &PacketType, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+    match row {
+        PacketType::HeadsetConnected(id) => write_row(out, 0, 0xd0, &id.to_be_bytes()),
+        PacketType::HeadsetNotFound(id) => write_row(out, 0, 0xd1, &id.to_be_bytes()),
+        PacketType::NoHeadsetFound => write_row(out, 0, 0xd1, &[]),
+        PacketType::HeadsetDisconnected(id) => write_row(out, 0, 0xd2, &id.to_be_bytes()),
+        PacketType::RequestDenied => write_row(out, 0, 0xd3, &[]),
+        PacketType::Standby => write_row(out, 0, 0xd4, &[0x00]),
+        PacketType::FindHeadset => write_row(out, 0, 0xd4, &[0x01]),
+        PacketType::PoorSignal(value) => write_row(out, 0, 0x02, &[*value]),
+        PacketType::HeartRate(value) => write_row(out, 0, 0x03, &[*value]),
+        PacketType::Attention(value) => write_row(out, 0, 0x04, &[*value]),
+        PacketType::Meditation(value) => write_row(out, 0, 0x05, &[*value]),
+        PacketType::Raw8Bit(value) => write_row(out, 0, 0x06, &[*value]),
+        PacketType::RawMarker(value) => write_row(out, 0, 0x07, &[*value]),
+        PacketType::Blink(value) => write_row(out, 0, 0x16, &[*value]),
+        PacketType::RawValue(value) => write_row(out, 0, 0x80, &value.to_be_bytes()),
+        PacketType::EegPower(value) => write_row(out, 0, 0x81, &encode_eeg_power(value)),
+        PacketType::AsicEeg(value) => write_row(out, 0, 0x83, &encode_asic_eeg(value)?),
+        PacketType::RrInterval(value) => write_row(out, 0, 0x86, &value.to_be_bytes()),
+        PacketType::ExtendedCode(level, code, value) => write_row(out, *level, *code, value),
+        PacketType::RawRow(code, value) => write_row(out, 0, *code, value),
+        PacketType::HeadsetConnectedUndefined
+        | PacketType::NotFoundUndefined
+        | PacketType::HeadsetDisconnectedUndefined
+        | PacketType::StandbyPacketUndefined
+        | PacketType::StandbyLengthUndefined
+        | PacketType::PacketUndefined(_) => Err(EncodeError::Unencodable),
+    }
+}
+
+// Appends an arbitrary data row: `level` EXCODE bytes, the code, VLENGTH for codes 0x80 and
+// above, then the value bytes
+pub fn write_row(out: &mut Vec<u8>, level: u8, code: u8, value: &[u8]) -> Result<(), EncodeError> {
+    if code == 0x55 || code == 0xaa {
+        return Err(EncodeError::BadRowLength(code, value.len()));
+    }
+    if code >= 0x80 {
+        if value.len() > 170 {
+            return Err(EncodeError::BadRowLength(code, value.len()));
+        }
+    } else if value.len() != 1 {
+        return Err(EncodeError::BadRowLength(code, value.len()));
+    }
+    out.resize(out.len() + level as usize, 0x55);
+    out.push(code);
+    if code >= 0x80 {
+        out.push(value.len() as u8);
+    }
+    out.extend_from_slice(value);
+    Ok(())
+}
+
+fn encode_asic_eeg(value: &AsicEeg) -> Result<Vec<u8>, EncodeError> {
+    let bands = [
+        value.delta,
+        value.theta,
+        value.low_alpha,
+        value.high_alpha,
+        value.low_beta,
+        value.high_beta,
+        value.low_gamma,
+        value.mid_gamma,
+    ];
+    let mut bytes = Vec::with_capacity(24);
+    for band in bands.iter() {
+        if *band > 0xff_ffff {
+            return Err(EncodeError::ValueOutOfRange);
+        }
+        bytes.extend_from_slice(&band.to_be_bytes()[1..]);
+    }
+    Ok(bytes)
+}
+
+fn encode_eeg_power(value: &EegPower) -> Vec<u8> {
+    let bands = [
+        value.delta,
+        value.theta,
+        value.low_alpha,
+        value.high_alpha,
+        value.low_beta,
+        value.high_beta,
+        value.low_gamma,
+        value.mid_gamma,
+    ];
+    bands.iter().flat_map(|band| band.to_be_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Parser, RowBytes};
+    use pretty_assertions::assert_eq;
+
+    fn parse_all(data: &[u8]) -> Vec<PacketType> {
+        let mut parser = Parser::new();
+        parser
+            .parse_slice(data)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_encode_packet() {
+        let rows = vec![
+            PacketType::PoorSignal(0x00),
+            PacketType::AsicEeg(AsicEeg {
+                delta: 0x94,
+                theta: 0x42,
+                low_alpha: 0x0b,
+                high_alpha: 0x64,
+                low_beta: 0x4d,
+                high_beta: 0x3d,
+                low_gamma: 0x07,
+                mid_gamma: 0x05,
+            }),
+            PacketType::Attention(0x0d),
+            PacketType::Meditation(0x3d),
+        ];
+
+        // the packet hand-written in the parser test
+        assert_eq!(
+            encode_packet(&rows).unwrap(),
+            vec![
+                0xAA, 0xAA, 0x20, 0x02, 0x00, 0x83, 0x18, 0x00, 0x00, 0x94, 0x00, 0x00, 0x42, 0x00,
+                0x00, 0x0B, 0x00, 0x00, 0x64, 0x00, 0x00, 0x4D, 0x00, 0x00, 0x3D, 0x00, 0x00, 0x07,
+                0x00, 0x00, 0x05, 0x04, 0x0D, 0x05, 0x3D, 0x34,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let rows = vec![
+            PacketType::HeadsetConnected(0xa05f),
+            PacketType::HeadsetNotFound(0xa05f),
+            PacketType::NoHeadsetFound,
+            PacketType::HeadsetDisconnected(0xa05f),
+            PacketType::RequestDenied,
+            PacketType::Standby,
+            PacketType::FindHeadset,
+            PacketType::PoorSignal(200),
+            PacketType::HeartRate(72),
+            PacketType::Attention(13),
+            PacketType::Meditation(61),
+            PacketType::Raw8Bit(0x7f),
+            PacketType::RawMarker(0x00),
+            PacketType::Blink(0x55),
+            PacketType::RawValue(-32768),
+            PacketType::EegPower(EegPower {
+                delta: 1.5,
+                theta: -2.25,
+                mid_gamma: 1e6,
+                ..EegPower::new()
+            }),
+            PacketType::AsicEeg(AsicEeg {
+                delta: 0xff_ffff,
+                mid_gamma: 1,
+                ..AsicEeg::new()
+            }),
+            PacketType::RrInterval(800),
+            PacketType::ExtendedCode(2, 0x90, RowBytes::from_slice(&[0x12, 0x34]).unwrap()),
+            PacketType::RawRow(0x90, RowBytes::from_slice(&[0xaa, 0xaa, 0x55]).unwrap()),
+        ];
+        let mut stream = Vec::new();
+        for row in rows.iter() {
+            stream.extend(encode_packet(std::slice::from_ref(row)).unwrap());
+        }
+        assert_eq!(parse_all(&stream), rows);
+
+        // every raw value survives a round trip, several rows per packet
+        let raw: Vec<PacketType> = (i16::MIN..=i16::MAX)
+            .step_by(7)
+            .map(PacketType::RawValue)
+            .collect();
+        let stream: Vec<u8> = raw
+            .chunks(42)
+            .flat_map(|rows| encode_packet(rows).unwrap())
+            .collect();
+        assert_eq!(parse_all(&stream), raw);
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        assert_eq!(
+            encode_packet(&vec![PacketType::RawValue(0); 43]),
+            Err(EncodeError::PayloadTooLong(172))
+        );
+        assert_eq!(
+            encode_packet(&[PacketType::AsicEeg(AsicEeg {
+                theta: 0x100_0000,
+                ..AsicEeg::new()
+            })]),
+            Err(EncodeError::ValueOutOfRange)
+        );
+        assert_eq!(
+            encode_packet(&[PacketType::RawRow(0x04, RowBytes::from_slice(&[]).unwrap())]),
+            Err(EncodeError::BadRowLength(0x04, 0))
+        );
+        assert_eq!(
+            encode_packet(&[PacketType::StandbyLengthUndefined]),
+            Err(EncodeError::Unencodable)
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{DongleStatus, EncodeError, ParseError};
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    // Opening or configuring the dongle's serial port failed, for instance because the device
+    // does not exist, is busy, the user lacks the permission to open it, or the port does not
+    // support a baud rate
+    Serial(serialport::Error),
+    // Reading from or writing to the transport failed
+    Io(io::Error),
+    Parse(ParseError),
+    Encode(EncodeError),
+    // The dongle did not answer a command in time
+    Timeout,
+    // The transport reached the end of its stream
+    Closed,
+    // Headset with this ID not found, or no headset found during auto-connect
+    NotFound(Option<u16>),
+    // The dongle denied the request
+    Denied,
+    // Headset ID that is neither two bytes long nor HEADSETID_AUTOCONNECT
+    InvalidHeadsetId(usize),
+    // The dongle answered a command with a status that does not answer it
+    UnexpectedStatus(DongleStatus),
+    // Reconnecting failed as many times as allowed
+    GaveUp,
+    // No serial port looks like a dongle
+    NoDongle,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Serial(e) => write!(f, "cannot open or configure the dongle port: {}", e),
+            Error::Io(e) => write!(f, "cannot read from or write to dongle: {}", e),
+            Error::Parse(e) => write!(f, "cannot decode packet: {}", e),
+            Error::Encode(e) => write!(f, "cannot encode packet: {}", e),
+            Error::Timeout => write!(f, "timed out waiting for the dongle to answer"),
+            Error::Closed => write!(f, "dongle closed the connection"),
+            Error::NotFound(Some(id)) => write!(
+                f,
+                "headset {:#06x} not found, please make sure the headset is turned on",
+                id
+            ),
+            Error::NotFound(None) => write!(
+                f,
+                "no headset found, please make sure the headset is turned on"
+            ),
+            Error::Denied => write!(f, "the dongle denied the request"),
+            Error::InvalidHeadsetId(len) => {
+                write!(f, "headset ID must be two bytes long, got {} bytes", len)
+            }
+            Error::UnexpectedStatus(status) => {
+                write!(f, "unexpected dongle status {:?}", status)
+            }
+            Error::GaveUp => write!(f, "gave up reconnecting to the headset"),
+            Error::NoDongle => write!(
+                f,
+                "no dongle found, please make sure the dongle is plugged in"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Serial(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Parse(e) => Some(e),
+            Error::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Error {
+        Error::Serial(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        Error::Parse(e)
+    }
+}
+
+impl From<EncodeError> for Error {
+    fn from(e: EncodeError) -> Error {
+        Error::Encode(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_serial_error() {
+        let e = Error::from(serialport::Error::new(
+            serialport::ErrorKind::NoDevice,
+            "No such file or directory",
+        ));
+        assert_eq!(
+            e.to_string(),
+            "cannot open or configure the dongle port: No such file or directory"
+        );
+        assert!(std::error::Error::source(&e).is_some());
+    }
+
+    #[test]
+    fn test_inner_errors() {
+        let e = Error::from(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Permission denied",
+        ));
+        assert_eq!(
+            e.to_string(),
+            "cannot read from or write to dongle: Permission denied"
+        );
+        let e = Error::from(ParseError::PlengthTooLarge(0xab));
+        assert_eq!(
+            e.to_string(),
+            "cannot decode packet: plength 171 larger than 170"
+        );
+        let e = Error::from(EncodeError::ValueOutOfRange);
+        assert_eq!(e.to_string(), "cannot encode packet: value out of range");
+    }
+}
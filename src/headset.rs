@@ -0,0 +1,299 @@
+use crate::{
+    AsicEeg, EegPower, Error, Event, PacketType, SessionEvent, Source, Supervisor, Transport,
+};
+use log::warn;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Handlers<T> = Vec<Box<dyn FnMut(T) + Send>>;
+type RefHandlers<T> = Vec<Box<dyn FnMut(&T) + Send>>;
+
+// Band powers of a packet: the ASIC's unsigned integers, or the float values some modules send
+// instead
+#[derive(PartialEq, Debug, Clone)]
+pub enum BandPower {
+    Asic(AsicEeg),
+    Float(EegPower),
+}
+
+#[derive(Default)]
+struct Observers {
+    attention: Handlers<u8>,
+    meditation: Handlers<u8>,
+    raw: Handlers<i16>,
+    band_power: RefHandlers<BandPower>,
+    blink: Handlers<u8>,
+    signal_quality: Handlers<u8>,
+    connection: Handlers<SessionEvent>,
+    subscribers: Vec<Sender<Event>>,
+}
+
+impl Observers {
+    fn notify(&mut self, event: &Event) {
+        match event {
+            Event::Row(PacketType::Attention(value), _) => {
+                self.attention.iter_mut().for_each(|f| f(*value))
+            }
+            Event::Row(PacketType::Meditation(value), _) => {
+                self.meditation.iter_mut().for_each(|f| f(*value))
+            }
+            Event::Row(PacketType::RawValue(value), _) => {
+                self.raw.iter_mut().for_each(|f| f(*value))
+            }
+            Event::Row(PacketType::AsicEeg(value), _) => {
+                let power = BandPower::Asic(value.clone());
+                self.band_power.iter_mut().for_each(|f| f(&power))
+            }
+            Event::Row(PacketType::EegPower(value), _) => {
+                let power = BandPower::Float(value.clone());
+                self.band_power.iter_mut().for_each(|f| f(&power))
+            }
+            Event::Row(PacketType::Blink(value), _) => {
+                self.blink.iter_mut().for_each(|f| f(*value))
+            }
+            Event::Row(PacketType::PoorSignal(value), _) => {
+                self.signal_quality.iter_mut().for_each(|f| f(*value))
+            }
+            Event::Session(e) => self.connection.iter_mut().for_each(|f| f(*e)),
+            _ => (),
+        }
+        self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+// Runs a supervised session on its own thread and hands the decoded values to the registered
+// handlers and subscribers. Handlers run on the session thread, one at a time, and must not
+// register further handlers. Dropping the handle stops the session like stop.
+pub struct Headset {
+    observers: Arc<Mutex<Observers>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl Headset {
+    // Handle without a session yet; register the handlers, then start it
+    pub fn new() -> Headset {
+        Headset {
+            observers: Arc::new(Mutex::new(Observers::default())),
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+
+    pub fn on_attention<F: FnMut(u8) + Send + 'static>(&self, f: F) -> &Headset {
+        self.observers().attention.push(Box::new(f));
+        self
+    }
+
+    pub fn on_meditation<F: FnMut(u8) + Send + 'static>(&self, f: F) -> &Headset {
+        self.observers().meditation.push(Box::new(f));
+        self
+    }
+
+    pub fn on_raw<F: FnMut(i16) + Send + 'static>(&self, f: F) -> &Headset {
+        self.observers().raw.push(Box::new(f));
+        self
+    }
+
+    // Band powers of both the ASIC and the float EEG power packets
+    pub fn on_band_power<F: FnMut(&BandPower) + Send + 'static>(&self, f: F) -> &Headset {
+        self.observers().band_power.push(Box::new(f));
+        self
+    }
+
+    pub fn on_blink<F: FnMut(u8) + Send + 'static>(&self, f: F) -> &Headset {
+        self.observers().blink.push(Box::new(f));
+        self
+    }
+
+    // Poor signal value from 0, good contact, to 200, no contact
+    pub fn on_signal_quality<F: FnMut(u8) + Send + 'static>(&self, f: F) -> &Headset {
+        self.observers().signal_quality.push(Box::new(f));
+        self
+    }
+
+    pub fn on_connection_change<F: FnMut(SessionEvent) + Send + 'static>(&self, f: F) -> &Headset {
+        self.observers().connection.push(Box::new(f));
+        self
+    }
+
+    // Channel receiving every event of the session. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.observers().subscribers.push(sender);
+        receiver
+    }
+
+    // Opens `source` and starts a session connecting to `headset`
+    pub fn open(&mut self, source: &Source, headset: &[u8]) -> Result<(), Error> {
+        let supervisor = source.supervise(headset)?;
+        self.start(supervisor);
+        Ok(())
+    }
+
+    // Starts reading the session on a new thread, stopping the previous session first
+    pub fn start<T: Transport + 'static>(&mut self, supervisor: Supervisor<T>) {
+        if let Err(e) = self.stop() {
+            warn!("previous session failed: {}", e);
+        }
+        let observers = self.observers.clone();
+        let running = self.running.clone();
+        running.store(true, Ordering::SeqCst);
+        let mut supervisor = supervisor.stop_flag(running.clone());
+        self.thread = Some(thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                match supervisor.next_event() {
+                    Ok(Some(event)) => observers.lock().unwrap().notify(&event),
+                    Ok(None) => break,
+                    Err(e) => {
+                        running.store(false, Ordering::SeqCst);
+                        return Err(e);
+                    }
+                }
+            }
+            running.store(false, Ordering::SeqCst);
+            Ok(())
+        }));
+    }
+
+    // False once the session has ended, failed or been stopped
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    // Stops the session and waits for its thread, returning the error that ended the session.
+    // A session waiting to reconnect stops right away, one reading a dongle within its read
+    // timeout. A source that blocks until it has data, such as stdin, cannot be interrupted:
+    // stopping it waits for its next bytes or its end.
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.running.store(false, Ordering::SeqCst);
+        match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(result) => result,
+                Err(e) => panic::resume_unwind(e),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn observers(&self) -> std::sync::MutexGuard<'_, Observers> {
+        self.observers.lock().unwrap()
+    }
+}
+
+impl Default for Headset {
+    fn default() -> Headset {
+        Headset::new()
+    }
+}
+
+impl Drop for Headset {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        // a panic on the session thread is not raised again while dropping
+        if let Some(Ok(Err(e))) = self.thread.take().map(JoinHandle::join) {
+            warn!("session failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::SimTransport;
+    use crate::{encode_packet, Dongle, ReadOnly, Scenario};
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+    use std::sync::mpsc::TryRecvError;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_headset_handlers() {
+        let (sender, values) = mpsc::channel();
+        let mut headset = Headset::new();
+        let attention = sender.clone();
+        headset
+            .on_connection_change(move |e| sender.send(format!("{:?}", e)).unwrap())
+            .on_attention(move |v| attention.send(format!("attention {}", v)).unwrap());
+        let events = headset.subscribe();
+
+        headset.start(Supervisor::new(
+            Dongle::new(SimTransport::new(0xa05f, Scenario::Normal)),
+            b"\xa0\x5f",
+        ));
+        let timeout = Duration::from_secs(5);
+        assert_eq!(values.recv_timeout(timeout).unwrap(), "Connected(41055)");
+        assert!(values
+            .recv_timeout(timeout)
+            .unwrap()
+            .starts_with("attention "));
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            Event::Session(SessionEvent::Connected(0xa05f))
+        );
+        assert!(headset.is_running());
+        headset.stop().unwrap();
+        assert!(!headset.is_running());
+    }
+
+    #[test]
+    fn test_headset_band_power() {
+        let asic = AsicEeg {
+            delta: 100,
+            ..AsicEeg::new()
+        };
+        let float = EegPower {
+            theta: 2.5,
+            ..EegPower::new()
+        };
+        let mut stream = encode_packet(&[PacketType::AsicEeg(asic.clone())]).unwrap();
+        stream.extend(encode_packet(&[PacketType::EegPower(float.clone())]).unwrap());
+
+        let (sender, powers) = mpsc::channel();
+        let mut headset = Headset::new();
+        headset.on_band_power(move |power| sender.send(power.clone()).unwrap());
+        headset.start(Supervisor::passive(Dongle::new(ReadOnly(Cursor::new(
+            stream,
+        )))));
+        let timeout = Duration::from_secs(5);
+        assert_eq!(powers.recv_timeout(timeout).unwrap(), BandPower::Asic(asic));
+        assert_eq!(
+            powers.recv_timeout(timeout).unwrap(),
+            BandPower::Float(float)
+        );
+        headset.stop().unwrap();
+    }
+
+    #[test]
+    fn test_headset_stop() {
+        // stopped while waiting to reconnect
+        let mut headset = Headset::new();
+        headset.start(
+            Supervisor::new(
+                Dongle::new(SimTransport::new(0xa05f, Scenario::HeadsetNotFound)),
+                b"\xa0\x5f",
+            )
+            .backoff(Duration::from_secs(60), Duration::from_secs(60)),
+        );
+        thread::sleep(Duration::from_millis(100));
+        let stopping = Instant::now();
+        headset.stop().unwrap();
+        assert!(stopping.elapsed() < Duration::from_secs(5));
+
+        // dropping the handle waits for the session thread, which holds the handlers
+        let (sender, values) = mpsc::channel();
+        let mut headset = Headset::new();
+        headset.on_attention(move |v| sender.send(v).unwrap());
+        headset.start(Supervisor::new(
+            Dongle::new(SimTransport::new(0xa05f, Scenario::Normal)),
+            b"\xa0\x5f",
+        ));
+        values.recv_timeout(Duration::from_secs(5)).unwrap();
+        drop(headset);
+        values.try_iter().for_each(drop);
+        assert_eq!(values.try_recv(), Err(TryRecvError::Disconnected));
+    }
+}
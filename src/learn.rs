@@ -1,101 +1,1347 @@
+mod cli;
+
 use anyhow::{anyhow, Result};
-use clap::{App, Arg};
-use env_logger;
-use hex::decode;
-use rustymind::{connect_headset, AsicEeg, PacketType, Parser, HEADSETID_AUTOCONNECT};
+use clap::{App, Arg, ArgMatches};
+use cli::SessionArgs;
+use hex::{decode, encode};
+use log::warn;
+use rustymind::{
+    AsicEeg, EdfFile, EdfFormat, EdfWriter, Event, PacketType, Replay, SessionManager, Source,
+    Speed, RAW_SAMPLE_RATE,
+};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Write;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Time base of the records of a session, carried from one record to the next. Stream time
+// is the time the events were read at, scaled back by the replay speed, so that it follows
+// the time the session was recorded.
+#[derive(Clone, Copy)]
+struct Timeline {
+    // speed the stream is read at relative to the time it was recorded, None when it is read
+    // as fast as possible, which leaves no clock
+    speed: Option<f64>,
+    // when the first event was read, on the monotonic and on the wall clock
+    start: Option<Instant>,
+    wall_start: SystemTime,
+    // stream time the current record's window started at: the previous record, or the first
+    // event
+    window: Option<Duration>,
+    // index of the first raw sample of the current record's window
+    samples: u64,
+}
+
+impl Timeline {
+    fn new(speed: Option<f64>) -> Timeline {
+        Timeline {
+            speed,
+            start: None,
+            wall_start: UNIX_EPOCH,
+            window: None,
+            samples: 0,
+        }
+    }
+
+    // Stream time of an event read at `at`
+    fn time(&mut self, at: Instant) -> Option<Duration> {
+        if self.start.is_none() {
+            self.start = Some(at);
+            self.wall_start = SystemTime::now()
+                .checked_sub(at.elapsed())
+                .unwrap_or_else(SystemTime::now);
+        }
+        let start = self.start.unwrap_or(at);
+        self.speed
+            .map(|speed| at.saturating_duration_since(start).mul_f64(speed))
+    }
+}
+
+// A live session
+impl Default for Timeline {
+    fn default() -> Timeline {
+        Timeline::new(Some(1.0))
+    }
+}
+
+// Speed the events of `source` are read at relative to the time they were recorded
+fn speed(source: &Source) -> Option<f64> {
+    match source {
+        Source::Replay(_, Speed::Factor(factor)) => Some(*factor),
+        Source::Replay(_, Speed::Max) | Source::File(_) => None,
+        _ => Some(1.0),
+    }
+}
+
+// Wall clock time a replayed capture started, so that the replay keeps the capture's times
+fn captured(source: &Source) -> Result<Option<SystemTime>> {
+    Ok(match source {
+        Source::Replay(path, _) => {
+            Replay::new(BufReader::new(File::open(path)?), Speed::Max)?.started()
+        }
+        _ => None,
+    })
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Train {
+    // Wall clock time the record was completed, in seconds since the Unix epoch: the time the
+    // session started plus monotonic
+    pub timestamp: f64,
+    // Monotonic time the record was completed, in seconds of stream time since the session
+    // started
+    pub monotonic: f64,
+    // Index of the first raw sample of the record among the raw samples the headset sent
+    // since the session started, dropped ones included
+    pub window_start: u64,
+    // Raw samples the headset sends at 512 Hz over the record's window, and the ones received
+    pub raw_expected: u64,
+    pub raw_received: u64,
     pub attention: u8,
     pub meditation: u8,
     pub poor_signal: u8,
     pub raw_val: Vec<i16>,
     pub eeg: AsicEeg,
+    #[serde(skip)]
+    timeline: Timeline,
 }
 
 impl Train {
-    fn new() -> Train {
+    // First record of a session read at `speed`, as given by `speed()`
+    fn new(speed: Option<f64>) -> Train {
         Train {
+            timestamp: 0.0,
+            monotonic: 0.0,
+            window_start: 0,
+            raw_expected: 0,
+            raw_received: 0,
             attention: 0,
             meditation: 0,
             poor_signal: 0,
             raw_val: Vec::new(),
             eeg: AsicEeg::new(),
+            timeline: Timeline::new(speed),
+        }
+    }
+
+    // Stamps the record with the time it is completed, in stream time, and the raw samples of
+    // its window. Without a clock every record spans the one second the headset sends eSense
+    // values in, but for the first one, which starts in the middle of a second.
+    fn complete(&mut self, time: Option<Duration>) -> Result<()> {
+        let timeline = &self.timeline;
+        let rate = RAW_SAMPLE_RATE as f64;
+        self.raw_received = self.raw_val.len() as u64;
+        match (time, timeline.window) {
+            (Some(time), Some(window)) => {
+                // both ends from the session start, so that rounding never adds up
+                self.window_start = (window.as_secs_f64() * rate).round() as u64;
+                let end = (time.as_secs_f64() * rate).round() as u64;
+                self.raw_expected = end.saturating_sub(self.window_start);
+                self.monotonic = time.as_secs_f64();
+            }
+            _ => {
+                self.window_start = timeline.samples;
+                self.raw_expected = match timeline.samples {
+                    0 => self.raw_received,
+                    _ => RAW_SAMPLE_RATE as u64,
+                };
+                self.monotonic = (self.window_start + self.raw_expected) as f64 / rate;
+            }
+        }
+        // on the same clock as monotonic, so that both tell the same time
+        let start = timeline.wall_start.duration_since(UNIX_EPOCH)?;
+        self.timestamp = start.as_secs_f64() + self.monotonic;
+        Ok(())
+    }
+
+    // Starts the next record, its window starting where this one ends
+    fn next(&mut self, time: Option<Duration>) {
+        let timeline = Timeline {
+            window: time,
+            samples: self.window_start + self.raw_expected,
+            ..self.timeline
+        };
+        *self = Train {
+            timeline,
+            ..Train::new(timeline.speed)
+        };
+    }
+
+    // Adds a session event read at `at` to the record, writing the record out once it is
+    // complete
+    fn record(&mut self, event: Event, at: Instant, output: &mut Output) -> Result<()> {
+        let time = self.timeline.time(at);
+        if self.timeline.window.is_none() {
+            self.timeline.window = time;
+        }
+        match event {
+            Event::Row(PacketType::RawValue(value), _) => {
+                self.raw_val.push(value);
+            }
+            Event::Row(PacketType::PoorSignal(value), _) => {
+                self.poor_signal = value;
+            }
+            Event::Row(PacketType::AsicEeg(value), _) => {
+                self.eeg = value;
+            }
+            Event::Row(PacketType::Attention(value), _) => {
+                self.attention = value;
+            }
+            Event::Row(PacketType::Meditation(value), _) => {
+                self.meditation = value;
+                self.complete(time)?;
+                output.write(self)?;
+                self.next(time);
+            }
+            Event::Row(PacketType::PacketUndefined(value), _) => {
+                warn!("undefined value = {}", value);
+            }
+            Event::Row(..) | Event::Session(_) => (),
+            Event::Error(e) => warn!("{}", e),
+        }
+        Ok(())
+    }
+}
+
+// Version of the CSV column layout, written in the first column of every row.
+//
+// Version 2, one row per training record:
+// version, record, timestamp, monotonic, window_start, raw_expected, raw_received, attention,
+// meditation, poor_signal, delta, theta, low_alpha, high_alpha, low_beta, high_beta,
+// low_gamma, mid_gamma
+// where record counts the rows from 0 and the other columns are the fields of Train.
+// Version 1 had neither monotonic, window_start, raw_expected nor raw_received.
+//
+// Raw file, one row per raw wave sample of a training record, unchanged since version 1:
+// version, record, sample, raw
+const CSV_VERSION: u32 = 2;
+
+#[derive(Serialize, Default)]
+struct CsvRecord {
+    version: u32,
+    record: u64,
+    timestamp: f64,
+    monotonic: f64,
+    window_start: u64,
+    raw_expected: u64,
+    raw_received: u64,
+    attention: u8,
+    meditation: u8,
+    poor_signal: u8,
+    delta: u32,
+    theta: u32,
+    low_alpha: u32,
+    high_alpha: u32,
+    low_beta: u32,
+    high_beta: u32,
+    low_gamma: u32,
+    mid_gamma: u32,
+}
+
+#[derive(Serialize, Default)]
+struct CsvRawSample {
+    version: u32,
+    record: u64,
+    sample: usize,
+    raw: i16,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "txt",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Mode {
+    // Appends to the output file, keeping the records of earlier runs
+    Append,
+    // Writes every run to a new file named after the time it started
+    New,
+}
+
+#[derive(Debug, Clone)]
+struct OutputOptions {
+    format: Format,
+    delimiter: u8,
+    header: bool,
+    // also writes the raw samples of every CSV record to a separate file
+    raw: bool,
+    mode: Mode,
+    // starts a new file once the current one is this large or this old
+    rotate_size: Option<u64>,
+    rotate_time: Option<Duration>,
+}
+
+// Written before the records of a session, and again at the top of every rotated file
+#[derive(Serialize)]
+struct SessionHeader {
+    // seconds since the Unix epoch
+    start: f64,
+    headset: String,
+    dongle: String,
+    version: &'static str,
+    subject: Option<String>,
+    label: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SessionLine<'a> {
+    session: &'a SessionHeader,
+}
+
+enum Sink {
+    Json(File),
+    Csv {
+        records: Box<csv::Writer<File>>,
+        raw: Option<Box<csv::Writer<File>>>,
+    },
+}
+
+impl Sink {
+    fn open(path: &Path, options: &OutputOptions) -> Result<Sink> {
+        let append = |path: &Path| OpenOptions::new().create(true).append(true).open(path);
+        let csv = |path: &Path| -> Result<Box<csv::Writer<File>>> {
+            let file = append(path)?;
+            // a file appended to has its header already
+            let header = options.header && file.metadata()?.len() == 0;
+            Ok(Box::new(
+                csv::WriterBuilder::new()
+                    .delimiter(options.delimiter)
+                    .has_headers(header)
+                    .from_writer(file),
+            ))
+        };
+        Ok(match options.format {
+            Format::Json => Sink::Json(append(path)?),
+            Format::Csv => Sink::Csv {
+                records: csv(path)?,
+                raw: match options.raw {
+                    true => Some(csv(&sibling(path, "_raw", "csv"))?),
+                    false => None,
+                },
+            },
+        })
+    }
+}
+
+// Where complete training records go. JSON records follow the session header in the same
+// file; CSV records go to the output file, with the raw samples in `<stem>_raw.csv` and the
+// session headers in `<stem>_sessions.txt` next to it.
+struct Output {
+    options: OutputOptions,
+    header: SessionHeader,
+    // output path as configured, and the file currently written with the time it was started
+    path: PathBuf,
+    current: PathBuf,
+    opened: SystemTime,
+    sink: Sink,
+    // records in the current file
+    count: u64,
+}
+
+impl Output {
+    fn create(path: &Path, header: SessionHeader, options: &OutputOptions) -> Result<Output> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let now = SystemTime::now();
+        let (current, opened) = match options.mode {
+            Mode::Append => {
+                // a file appended to keeps the time it was started, and a file written by
+                // another version or with other options is moved aside first
+                let started = started(path, options.format)?;
+                if !compatible(path, &header, options)? {
+                    set_aside(path, started.unwrap_or(now))?;
+                    (path.to_path_buf(), now)
+                } else {
+                    (path.to_path_buf(), started.unwrap_or(now))
+                }
+            }
+            Mode::New => (dated(path, now)?, now),
+        };
+        let mut output = Output {
+            options: options.clone(),
+            header,
+            path: path.to_path_buf(),
+            count: records(&current, options)?,
+            sink: Sink::open(&current, options)?,
+            current,
+            opened,
+        };
+        output.write_header()?;
+        Ok(output)
+    }
+
+    fn write(&mut self, train: &Train) -> Result<()> {
+        if self.rotation_due()? {
+            self.rotate()?;
+        }
+        match &mut self.sink {
+            Sink::Json(file) => {
+                let mut j = serde_json::to_string(train)?;
+                j.push('\n');
+                file.write_all(j.as_bytes())?;
+            }
+            Sink::Csv { records, raw } => {
+                records.serialize(CsvRecord {
+                    version: CSV_VERSION,
+                    record: self.count,
+                    timestamp: train.timestamp,
+                    monotonic: train.monotonic,
+                    window_start: train.window_start,
+                    raw_expected: train.raw_expected,
+                    raw_received: train.raw_received,
+                    attention: train.attention,
+                    meditation: train.meditation,
+                    poor_signal: train.poor_signal,
+                    delta: train.eeg.delta,
+                    theta: train.eeg.theta,
+                    low_alpha: train.eeg.low_alpha,
+                    high_alpha: train.eeg.high_alpha,
+                    low_beta: train.eeg.low_beta,
+                    high_beta: train.eeg.high_beta,
+                    low_gamma: train.eeg.low_gamma,
+                    mid_gamma: train.eeg.mid_gamma,
+                })?;
+                records.flush()?;
+                if let Some(raw) = raw {
+                    for (sample, value) in train.raw_val.iter().enumerate() {
+                        raw.serialize(CsvRawSample {
+                            version: CSV_VERSION,
+                            record: self.count,
+                            sample,
+                            raw: *value,
+                        })?;
+                    }
+                    raw.flush()?;
+                }
+            }
         }
+        self.count += 1;
+        Ok(())
     }
 
-    fn write<W: Write>(&mut self, mut writer: W) -> Result<()> {
-        let mut j = serde_json::to_string(&self)?;
-        j.push_str(&"\n");
-        writer.write_all(&j.as_bytes())?;
-        *self = Train::new();
+    // Makes sure every record written has reached the disk
+    fn finish(self) -> Result<()> {
+        match self.sink {
+            Sink::Json(file) => file.sync_all()?,
+            Sink::Csv { records, raw } => {
+                for writer in Some(records).into_iter().chain(raw) {
+                    writer
+                        .into_inner()
+                        .map_err(|e| anyhow!("{}", e.error()))?
+                        .sync_all()?;
+                }
+            }
+        }
         Ok(())
     }
+
+    // A file is full once it is large or old enough, but never before its first record
+    fn rotation_due(&self) -> Result<bool> {
+        if self.count == 0 {
+            return Ok(false);
+        }
+        if let Some(age) = self.options.rotate_time {
+            if self.opened.elapsed().is_ok_and(|elapsed| elapsed >= age) {
+                return Ok(true);
+            }
+        }
+        if let Some(size) = self.options.rotate_size {
+            if fs::metadata(&self.current)?.len() >= size {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Starts a new file. In append mode the full file is moved aside, named after the time
+    // it was started, and the output path starts over.
+    fn rotate(&mut self) -> Result<()> {
+        let now = SystemTime::now();
+        self.current = match self.options.mode {
+            Mode::Append => {
+                set_aside(&self.path, self.opened)?;
+                self.path.clone()
+            }
+            Mode::New => dated(&self.path, now)?,
+        };
+        self.opened = now;
+        self.count = 0;
+        self.sink = Sink::open(&self.current, &self.options)?;
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let mut line = serde_json::to_string(&SessionLine {
+            session: &self.header,
+        })?;
+        line.push('\n');
+        match &mut self.sink {
+            Sink::Json(file) => file.write_all(line.as_bytes())?,
+            Sink::Csv { .. } => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(sibling(&self.current, "_sessions", "txt"))?
+                .write_all(line.as_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+// `path` with `suffix` added to its file stem and the given extension
+fn sibling(path: &Path, suffix: &str, extension: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}{}.{}", stem, suffix, extension))
+}
+
+// Output file and the files written next to it
+fn companions(path: &Path) -> [PathBuf; 3] {
+    [
+        path.to_path_buf(),
+        sibling(path, "_raw", "csv"),
+        sibling(path, "_sessions", "txt"),
+    ]
+}
+
+// Moves the output file at `path` and the files next to it aside, named after `time`
+fn set_aside(path: &Path, time: SystemTime) -> Result<()> {
+    let aside = dated(path, time)?;
+    for (from, to) in companions(path).iter().zip(companions(&aside).iter()) {
+        if from.exists() {
+            fs::rename(from, to)?;
+        }
+    }
+    Ok(())
+}
+
+fn first_line(path: &Path) -> Result<Option<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(BufReader::new(file).lines().next().transpose()?)
+}
+
+// Start of the first session written to the output file at `path`, if any
+fn started(path: &Path, format: Format) -> Result<Option<SystemTime>> {
+    let headers = match format {
+        Format::Json => path.to_path_buf(),
+        Format::Csv => sibling(path, "_sessions", "txt"),
+    };
+    let start = first_line(&headers)?
+        .and_then(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+        .and_then(|line| line["session"]["start"].as_f64());
+    Ok(start.map(|start| UNIX_EPOCH + Duration::from_secs_f64(start)))
+}
+
+// Whether records written with `options` can be appended to the output file at `path`: it is
+// missing or empty, or it was written by the same version in JSON, or with the same CSV
+// layout, delimiter and header
+fn compatible(path: &Path, header: &SessionHeader, options: &OutputOptions) -> Result<bool> {
+    match options.format {
+        Format::Json => Ok(match first_line(path)? {
+            Some(line) => serde_json::from_str::<serde_json::Value>(&line)
+                .is_ok_and(|line| line["session"]["version"] == header.version),
+            None => true,
+        }),
+        Format::Csv => Ok(csv_compatible::<CsvRecord>(path, options)?
+            && csv_compatible::<CsvRawSample>(&sibling(path, "_raw", "csv"), options)?),
+    }
+}
+
+fn csv_compatible<T: Serialize + Default>(path: &Path, options: &OutputOptions) -> Result<bool> {
+    if !path.exists() {
+        return Ok(true);
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+    let first = match reader.records().next() {
+        Some(Ok(first)) => first,
+        Some(Err(_)) => return Ok(false),
+        None => return Ok(true),
+    };
+    let header = csv_header::<T>()?;
+    Ok(match options.header {
+        true => first.iter().eq(header.iter()),
+        false => first.len() == header.len() && first[0] == CSV_VERSION.to_string(),
+    })
+}
+
+// Header row of the CSV files holding `T`s
+fn csv_header<T: Serialize + Default>() -> Result<csv::StringRecord> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.serialize(T::default())?;
+    let written = writer.into_inner()?;
+    Ok(csv::Reader::from_reader(&written[..]).headers()?.clone())
+}
+
+// Records already in the CSV file at `path`, so that appended records go on numbering them
+fn records(path: &Path, options: &OutputOptions) -> Result<u64> {
+    if options.format == Format::Json || !path.exists() {
+        return Ok(0);
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .has_headers(options.header)
+        .from_path(path)?;
+    Ok(reader.records().count() as u64)
+}
+
+// Unused path next to `path` named after `time`, in seconds since the Unix epoch
+fn dated(path: &Path, time: SystemTime) -> Result<PathBuf> {
+    let secs = time.duration_since(UNIX_EPOCH)?.as_secs();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let mut candidate = sibling(path, &format!("-{}", secs), &extension);
+    let mut n = 1;
+    while candidate.exists() {
+        candidate = sibling(path, &format!("-{}-{}", secs, n), &extension);
+        n += 1;
+    }
+    Ok(candidate)
+}
+
+// Where the EDF+ recording goes. A recording is never overwritten: in append mode an existing
+// one is moved aside, named after the time it started, and in new mode every recording is
+// named after the time it starts.
+fn edf_path(path: &Path, mode: Mode) -> Result<PathBuf> {
+    let now = SystemTime::now();
+    if mode == Mode::New {
+        return dated(path, now);
+    }
+    if path.exists() {
+        let started = File::open(path)
+            .ok()
+            .and_then(|file| EdfFile::read(BufReader::new(file)).ok())
+            .and_then(|edf| edf.start());
+        fs::rename(path, dated(path, started.unwrap_or(now))?)?;
+    }
+    Ok(path.to_path_buf())
+}
+
+fn session_header(headset: &[u8], dongle: &str, matches: &ArgMatches) -> SessionHeader {
+    let start = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    SessionHeader {
+        start: (start.as_millis() as f64) / 1000.0,
+        headset: encode(headset),
+        dongle: dongle.to_string(),
+        version: env!("CARGO_PKG_VERSION"),
+        subject: matches.value_of("subject").map(str::to_string),
+        label: matches.value_of("label").map(str::to_string),
+    }
+}
+
+// Records every `DONGLE_PATH:HEADSET_ID` session into its own file, named after the output
+// path, the position of the session on the command line and the headset ID
+fn record_sessions<'a, I: Iterator<Item = &'a str>>(
+    sessions: I,
+    output: &Path,
+    options: &OutputOptions,
+    matches: &ArgMatches,
+) -> Result<()> {
+    let mut manager = SessionManager::new();
+    let mut records = Vec::new();
+    for session in sessions {
+        let (path, id) = session
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Session {} is not DONGLE_PATH:HEADSET_ID", session))?;
+        let headset = decode(id)?;
+        let source = Source::Serial(path.to_string());
+        let mut supervisor = source.supervise(&headset)?;
+        if let Some(attempts) = matches.value_of("max-attempts") {
+            supervisor = supervisor.max_attempts(attempts.parse()?);
+        }
+        let session = manager.add_supervisor(&source.label(), &headset, supervisor);
+        let extension = options.format.extension();
+        let output = Output::create(
+            &sibling(output, &format!("_{}_{}", session.index, id), extension),
+            session_header(&headset, path, matches),
+            options,
+        )?;
+        records.push((output, Train::new(Some(1.0))));
+    }
+
+    let stats = matches
+        .value_of("stats")
+        .map(|v| Duration::from_secs(v.parse().unwrap()));
+    let running = manager.running();
+    ctrlc::set_handler(move || {
+        running.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let mut stats_time = Instant::now();
+    // Ctrl-C stops the sessions, and the loop ends once they have
+    let result = (|| -> Result<()> {
+        while let Some(tagged) = manager.next_event() {
+            if let Some(interval) = stats {
+                if stats_time.elapsed() >= interval {
+                    for (session, stats) in manager.stats() {
+                        eprintln!("{}: {}", session.label, stats);
+                    }
+                    stats_time = Instant::now();
+                }
+            }
+            match tagged {
+                Ok(tagged) => {
+                    if let Event::Session(e) = &tagged.event {
+                        eprintln!("{}: {:?}", tagged.session.label, e);
+                    }
+                    let (output, train_data) = &mut records[tagged.session.index];
+                    train_data.record(tagged.event, tagged.received, output)?;
+                }
+                Err(e) => eprintln!("{}: {}", e.session.label, e.error),
+            }
+        }
+        Ok(())
+    })();
+    manager.stop();
+    let finished = records
+        .into_iter()
+        .try_for_each(|(output, _)| output.finish());
+    result?;
+    finished
+}
+
+// Lines typed on stdin, with the time they were read
+fn read_markers() -> Receiver<(Instant, String)> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if sender.send((Instant::now(), line)).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
 }
 
-#[allow(unreachable_code)]
 fn main() -> Result<()> {
     env_logger::init();
-    let matches = App::new("rustymind")
+    let app = App::new("rustymind")
         .version("1.0")
         .author("Junjun Dong <junjun.dong9@gmail.com>")
-        .about("parse mindwaves and draw real time plots")
+        .about("parse mindwaves and draw real time plots");
+    let matches = cli::source_args(app, &["session"])
         .arg(
-            Arg::with_name("dongle-path")
-                .help("Sets the dongle path")
-                .required(true),
+            Arg::with_name("session")
+                .long("session")
+                .value_name("DONGLE_PATH:HEADSET_ID")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["dongle-path", "file", "stdin", "auto", "replay", "capture"])
+                .help("Records a headset into its own file; repeat to record several headsets at once"),
+        )
+        .arg(
+            Arg::with_name("edf")
+                .long("edf")
+                .value_name("PATH")
+                .takes_value(true)
+                .conflicts_with("session")
+                .help("Also records the raw wave, band powers and events to an EDF+ file, or a BDF+ file when PATH ends in .bdf"),
+        )
+        .arg(
+            Arg::with_name("markers")
+                .long("markers")
+                .requires("edf")
+                .conflicts_with("stdin")
+                .help("Reads markers from stdin, one per line, and adds them to the EDF+ file as annotations"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["json", "csv"])
+                .default_value("json")
+                .help("Writes the training records as JSON lines or as CSV"),
+        )
+        .arg(
+            Arg::with_name("delimiter")
+                .long("delimiter")
+                .value_name("CHAR")
+                .takes_value(true)
+                .default_value(",")
+                .help("Sets the CSV field delimiter, a single ASCII character or tab"),
+        )
+        .arg(
+            Arg::with_name("no-header")
+                .long("no-header")
+                .help("Leaves out the CSV header row"),
+        )
+        .arg(
+            Arg::with_name("raw")
+                .long("raw")
+                .help("Also writes the raw wave samples of every CSV record to a separate file; JSON records carry them already"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Sets the training records file, ./train_data/train_data.txt or .csv by default"),
+        )
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .value_name("MODE")
+                .takes_value(true)
+                .possible_values(&["append", "new"])
+                .default_value("append")
+                .help("Appends to the output file, or writes a new file named after the start time"),
+        )
+        .arg(
+            Arg::with_name("rotate-size")
+                .long("rotate-size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .validator(cli::number)
+                .help("Starts a new file once the current one reaches BYTES bytes"),
+        )
+        .arg(
+            Arg::with_name("rotate-time")
+                .long("rotate-time")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .validator(cli::number)
+                .help("Starts a new file once the current one is SECONDS seconds old"),
+        )
+        .arg(
+            Arg::with_name("subject")
+                .long("subject")
+                .value_name("TEXT")
+                .takes_value(true)
+                .help("Sets the subject written in the session header"),
+        )
+        .arg(
+            Arg::with_name("label")
+                .long("label")
+                .value_name("TEXT")
+                .takes_value(true)
+                .help("Sets the label written in the session header"),
         )
-        .arg(Arg::with_name("HEADSET_ID").help(
-            "Sets the headset ID. Set headset ID to 0xc2 to switch into auto-connect mode and connect to any to any headsets dongle can find",
-        ))
         .get_matches();
-    let headset = matches
-        .value_of("HEADSET_ID")
-        .map_or(HEADSETID_AUTOCONNECT.to_vec(), |v| {
-            decode(v).expect("Hex decoding failed")
-        });
-    let path = matches.value_of("dongle-path").unwrap();
-    let mut port = connect_headset(path, &headset[..]).map_err(|s| anyhow!(s))?;
-    let mut read_buf: Vec<u8> = vec![0; 2048];
-    let mut parser = Parser::new();
-    let mut buffer = File::create("./train_data/train_data.txt")?;
-    let mut train_data = Train::new();
-
-    loop {
-        let bytes_read = port.read(read_buf.as_mut_slice()).expect(
-            "Found no data when reading from dongle. Please make sure headset is connected.",
-        );
-        for i in 0..bytes_read {
-            if let Some(x) = parser.parse(read_buf[i]) {
-                for r in x {
-                    match r {
-                        PacketType::RawValue(value) => {
-                            train_data.raw_val.push(value);
-                        }
-                        PacketType::PoorSignal(value) => {
-                            train_data.poor_signal = value;
-                        }
-                        PacketType::AsicEeg(value) => {
-                            train_data.eeg = value;
-                        }
-                        PacketType::Attention(value) => {
-                            train_data.attention = value;
-                        }
-                        PacketType::Meditation(value) => {
-                            train_data.meditation = value;
-                            train_data.write(&mut buffer)?;
-                        }
-                        PacketType::PacketUndefined(value) => {
-                            println!("undefinded value = {}", value);
-                        }
-                        _ => (),
+    let delimiter = match matches.value_of("delimiter").unwrap() {
+        "tab" => b'\t',
+        d if d.len() == 1 && d.is_ascii() => d.as_bytes()[0],
+        d => return Err(anyhow!("Delimiter {} is not a single ASCII character", d)),
+    };
+    if matches.is_present("raw") && matches.value_of("format") != Some("csv") {
+        return Err(anyhow!(
+            "--raw needs --format csv, JSON records carry the raw samples already"
+        ));
+    }
+    let options = OutputOptions {
+        format: match matches.value_of("format") {
+            Some("csv") => Format::Csv,
+            _ => Format::Json,
+        },
+        delimiter,
+        header: !matches.is_present("no-header"),
+        raw: matches.is_present("raw"),
+        mode: match matches.value_of("mode") {
+            Some("new") => Mode::New,
+            _ => Mode::Append,
+        },
+        rotate_size: matches.value_of("rotate-size").map(|v| v.parse().unwrap()),
+        rotate_time: matches
+            .value_of("rotate-time")
+            .map(|v| Duration::from_secs(v.parse().unwrap())),
+    };
+    let output_path = matches.value_of("output").map_or_else(
+        || {
+            PathBuf::from(format!(
+                "./train_data/train_data.{}",
+                options.format.extension()
+            ))
+        },
+        PathBuf::from,
+    );
+    if let Some(sessions) = matches.values_of("session") {
+        return record_sessions(sessions, &output_path, &options, &matches);
+    }
+    let args = SessionArgs::from_matches(&matches)?;
+    let captured = captured(&args.source)?;
+    // a replay starts with the session
+    let opened = Instant::now();
+    let events = args.spawn()?;
+    let headset = &args.headset;
+    let dongle = args.source.label();
+    let header = session_header(headset, &dongle, &matches);
+    let mut output = Output::create(&output_path, header, &options)?;
+    let mut train_data = Train::new(speed(&args.source));
+    let mut edf = match matches.value_of("edf") {
+        Some(path) => {
+            let format = if path.to_lowercase().ends_with(".bdf") {
+                EdfFormat::Bdf
+            } else {
+                EdfFormat::Edf
+            };
+            let file = File::create(edf_path(Path::new(path), options.mode)?)?;
+            let writer = EdfWriter::new(BufWriter::new(file), format).speed(speed(&args.source));
+            Some(match captured {
+                Some(started) => writer.origin(opened, started),
+                None => writer,
+            })
+        }
+        None => None,
+    };
+    let markers = match matches.is_present("markers") {
+        true => Some(read_markers()),
+        false => None,
+    };
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let result = (|| -> Result<()> {
+        while running.load(Ordering::SeqCst) {
+            if let (Some(edf), Some(markers)) = (&mut edf, &markers) {
+                for (at, marker) in markers.try_iter() {
+                    match marker.trim() {
+                        "" => (),
+                        marker => edf.annotate_at(marker, at),
                     }
                 }
             }
+            // wake up now and then to notice Ctrl-C while the session reconnects
+            let event = match events.recv_timeout(Duration::from_millis(200)) {
+                Ok(event) => event?,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Event::Session(e) = &event {
+                eprintln!("{:?}", e);
+            }
+            if let Some(edf) = &mut edf {
+                edf.record(&event)?;
+            }
+            // rows carry the time their packet was read
+            let at = match &event {
+                Event::Row(_, info) => info.received,
+                _ => Instant::now(),
+            };
+            train_data.record(event, at, &mut output)?;
+        }
+        Ok(())
+    })();
+    // the EDF+ file is finished however the session ended, so that it stays readable
+    let finished = edf.map(EdfWriter::finish).transpose();
+    let synced = output.finish();
+    result?;
+    finished?;
+    synced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rustymind::PacketInfo;
+
+    fn defaults(format: Format) -> OutputOptions {
+        OutputOptions {
+            format,
+            delimiter: b',',
+            header: true,
+            raw: false,
+            mode: Mode::Append,
+            rotate_size: None,
+            rotate_time: None,
         }
     }
-    Ok(())
+
+    fn header() -> SessionHeader {
+        SessionHeader {
+            start: 1_792_281_600.25,
+            headset: "a05f".to_string(),
+            dongle: "/dev/ttyUSB0".to_string(),
+            version: "0.1.3",
+            subject: Some("s01".to_string()),
+            label: None,
+        }
+    }
+
+    fn train(attention: u8) -> Train {
+        let mut train = Train::new(Some(1.0));
+        train.timestamp = 1_792_281_601.5;
+        train.monotonic = 1.25;
+        train.window_start = 512;
+        train.raw_expected = 512;
+        train.raw_received = 3;
+        train.attention = attention;
+        train.meditation = 60;
+        train.raw_val = vec![-1, 0, 1];
+        train.eeg = AsicEeg {
+            delta: 1,
+            mid_gamma: 8,
+            ..AsicEeg::new()
+        };
+        train
+    }
+
+    // Empty directory of its own for a test
+    fn directory(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustymind-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_csv_schema() {
+        let dir = directory("csv-schema");
+        let path = dir.join("train_data.csv");
+        let options = OutputOptions {
+            delimiter: b';',
+            raw: true,
+            ..defaults(Format::Csv)
+        };
+        let mut output = Output::create(&path, header(), &options).unwrap();
+        output.write(&train(40)).unwrap();
+        output.write(&train(41)).unwrap();
+
+        assert_eq!(
+            lines(&path),
+            vec![
+                "version;record;timestamp;monotonic;window_start;raw_expected;raw_received;\
+                 attention;meditation;poor_signal;delta;theta;low_alpha;high_alpha;low_beta;\
+                 high_beta;low_gamma;mid_gamma",
+                "2;0;1792281601.5;1.25;512;512;3;40;60;0;1;0;0;0;0;0;0;8",
+                "2;1;1792281601.5;1.25;512;512;3;41;60;0;1;0;0;0;0;0;0;8",
+            ]
+        );
+        assert_eq!(
+            lines(&dir.join("train_data_raw.csv")),
+            vec![
+                "version;record;sample;raw",
+                "2;0;0;-1",
+                "2;0;1;0",
+                "2;0;2;1",
+                "2;1;0;-1",
+                "2;1;1;0",
+                "2;1;2;1",
+            ]
+        );
+        assert_eq!(
+            lines(&dir.join("train_data_sessions.txt")),
+            vec![
+                "{\"session\":{\"start\":1792281600.25,\"headset\":\"a05f\",\"dongle\":\"/dev/ttyUSB0\",\
+                 \"version\":\"0.1.3\",\"subject\":\"s01\",\"label\":null}}"
+            ]
+        );
+
+        // without a header the rows start right away, in the same column order
+        let path = dir.join("bare.csv");
+        let options = OutputOptions {
+            delimiter: b'\t',
+            header: false,
+            ..defaults(Format::Csv)
+        };
+        let mut output = Output::create(&path, header(), &options).unwrap();
+        output.write(&train(40)).unwrap();
+        assert_eq!(
+            lines(&path),
+            vec!["2\t0\t1792281601.5\t1.25\t512\t512\t3\t40\t60\t0\t1\t0\t0\t0\t0\t0\t0\t8"]
+        );
+        assert!(!dir.join("bare_raw.csv").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Files in `dir` whose names start with `prefix`, sorted
+    fn files(dir: &Path, prefix: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_append_mode() {
+        let dir = directory("append");
+        let path = dir.join("train_data.csv");
+        let options = OutputOptions {
+            raw: true,
+            ..defaults(Format::Csv)
+        };
+        for attention in [40, 41].iter() {
+            let mut output = Output::create(&path, header(), &options).unwrap();
+            output.write(&train(*attention)).unwrap();
+        }
+        // the second run goes on numbering the records under the one header row
+        let rows = lines(&path);
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].starts_with("version,record,"));
+        assert!(rows[1].starts_with("2,0,") && rows[2].starts_with("2,1,"));
+        assert_eq!(lines(&dir.join("train_data_raw.csv"))[4], "2,1,0,-1");
+        assert_eq!(lines(&dir.join("train_data_sessions.txt")).len(), 2);
+
+        // another delimiter moves the file aside with its companions, named after its start
+        let options = OutputOptions {
+            delimiter: b';',
+            ..options
+        };
+        let mut output = Output::create(&path, header(), &options).unwrap();
+        output.write(&train(42)).unwrap();
+        assert_eq!(
+            files(&dir, "train_data-"),
+            vec![
+                "train_data-1792281600.csv",
+                "train_data-1792281600_raw.csv",
+                "train_data-1792281600_sessions.txt"
+            ]
+        );
+        assert_eq!(lines(&dir.join("train_data-1792281600.csv")), rows);
+        let rows = lines(&path);
+        assert!(rows[0].starts_with("version;record;"));
+        assert!(rows[1].starts_with("2;0;"));
+        assert_eq!(lines(&dir.join("train_data_sessions.txt")).len(), 1);
+
+        // so does a file of an older layout
+        let old = dir.join("old.csv");
+        fs::write(
+            &old,
+            "version,record,timestamp,attention\n1,0,1792281000.5,40\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("old_sessions.txt"),
+            "{\"session\":{\"start\":1792281000.0}}\n",
+        )
+        .unwrap();
+        Output::create(&old, header(), &defaults(Format::Csv)).unwrap();
+        assert_eq!(lines(&dir.join("old-1792281000.csv")).len(), 2);
+        assert!(lines(&old).is_empty());
+
+        // and a JSON file of another version
+        let json = dir.join("train_data.txt");
+        let mut output = Output::create(&json, header(), &defaults(Format::Json)).unwrap();
+        output.write(&train(40)).unwrap();
+        Output::create(&json, header(), &defaults(Format::Json)).unwrap();
+        assert_eq!(lines(&json).len(), 3);
+        let newer = SessionHeader {
+            version: "0.2.0",
+            ..header()
+        };
+        Output::create(&json, newer, &defaults(Format::Json)).unwrap();
+        assert_eq!(lines(&dir.join("train_data-1792281600.txt")).len(), 3);
+        assert!(lines(&json)[0].contains("\"version\":\"0.2.0\""));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_new_mode() {
+        let dir = directory("new");
+        let path = dir.join("train_data.txt");
+        let options = OutputOptions {
+            mode: Mode::New,
+            ..defaults(Format::Json)
+        };
+        for attention in [40, 41].iter() {
+            let mut output = Output::create(&path, header(), &options).unwrap();
+            output.write(&train(*attention)).unwrap();
+        }
+        assert!(!path.exists());
+        let names = files(&dir, "train_data");
+        assert_eq!(names.len(), 2);
+        for name in names.iter() {
+            assert!(name.starts_with("train_data-"));
+            let rows = lines(&dir.join(name));
+            assert!(rows[0].starts_with("{\"session\":{\"start\":1792281600.25,"));
+            assert_eq!(rows.len(), 2);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_edf_path() {
+        let dir = directory("edf");
+        let path = dir.join("session.edf");
+        let record = |path: &Path| {
+            let start = UNIX_EPOCH + Duration::from_secs(1_792_281_600);
+            let file = BufWriter::new(File::create(path).unwrap());
+            EdfWriter::new(file, EdfFormat::Edf)
+                .start(start)
+                .finish()
+                .unwrap();
+        };
+
+        // an existing recording is moved aside, named after the time it started
+        record(&path);
+        assert_eq!(edf_path(&path, Mode::Append).unwrap(), path);
+        assert!(!path.exists());
+        assert_eq!(files(&dir, "session"), vec!["session-1792281600.edf"]);
+
+        // a new recording gets a name of its own, leaving the others alone
+        record(&path);
+        let new = edf_path(&path, Mode::New).unwrap();
+        assert!(new.file_name().unwrap() != "session.edf");
+        assert!(new.to_string_lossy().ends_with(".edf"));
+        assert_eq!(files(&dir, "session").len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = directory("rotation");
+        // every file is full after its first record
+        let path = dir.join("train_data.txt");
+        let options = OutputOptions {
+            rotate_size: Some(1),
+            ..defaults(Format::Json)
+        };
+        let mut output = Output::create(&path, header(), &options).unwrap();
+        for attention in [40, 41, 42].iter() {
+            output.write(&train(*attention)).unwrap();
+        }
+        // the file was moved aside twice, and every file starts with the session header
+        let names = files(&dir, "train_data");
+        assert_eq!(names.len(), 3);
+        let mut attention = Vec::new();
+        for name in names.iter() {
+            let rows = lines(&dir.join(name));
+            assert_eq!(rows.len(), 2);
+            assert!(rows[0].starts_with("{\"session\":"));
+            let record: serde_json::Value = serde_json::from_str(&rows[1]).unwrap();
+            attention.push(record["attention"].as_u64().unwrap());
+        }
+        attention.sort_unstable();
+        assert_eq!(attention, vec![40, 41, 42]);
+        assert!(names.contains(&"train_data.txt".to_string()));
+
+        // CSV files take their raw samples and session headers along
+        let path = dir.join("records.csv");
+        let options = OutputOptions {
+            raw: true,
+            rotate_size: Some(1),
+            ..defaults(Format::Csv)
+        };
+        let mut output = Output::create(&path, header(), &options).unwrap();
+        output.write(&train(40)).unwrap();
+        output.write(&train(41)).unwrap();
+        let names = files(&dir, "records-");
+        assert_eq!(names.len(), 3);
+        let aside = dir.join(&names[0]);
+        assert_eq!(
+            lines(&aside)[1],
+            "2,0,1792281601.5,1.25,512,512,3,40,60,0,1,0,0,0,0,0,0,8"
+        );
+        assert_eq!(lines(&sibling(&aside, "_raw", "csv")).len(), 4);
+        assert_eq!(lines(&sibling(&aside, "_sessions", "txt")).len(), 1);
+        assert!(lines(&path)[1].starts_with("2,0,1792281601.5,1.25,512,512,3,41,"));
+        assert_eq!(lines(&dir.join("records_raw.csv")).len(), 4);
+        assert_eq!(lines(&dir.join("records_sessions.txt")).len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Records of a session whose raw samples 256 to 2047 are read at `speed`, the eSense
+    // values once per second and samples 700 to 799 dropped
+    fn timeline(test: &str, speed: Option<f64>) -> Vec<(f64, u64, u64, u64)> {
+        let dir = directory(test);
+        let path = dir.join("train_data.txt");
+        let mut output = Output::create(&path, header(), &defaults(Format::Json)).unwrap();
+        let start = Instant::now();
+        // read time of the `k`th sample, the first one read at `start`
+        let at = |k: u32| {
+            let stream = Duration::from_secs(1) * (k - 256) / RAW_SAMPLE_RATE;
+            speed.map_or(start, |speed| start + stream.div_f64(speed))
+        };
+        let row = |row| {
+            Event::Row(
+                row,
+                PacketInfo {
+                    sequence: 0,
+                    received: start,
+                },
+            )
+        };
+        let mut train = Train::new(speed);
+        for k in 256..2048 {
+            if k % RAW_SAMPLE_RATE == 0 {
+                let events = [PacketType::Attention(40), PacketType::Meditation(60)];
+                for event in events.iter() {
+                    train
+                        .record(row(event.clone()), at(k), &mut output)
+                        .unwrap();
+                }
+            }
+            if !(700..800).contains(&k) {
+                let event = row(PacketType::RawValue(k as i16));
+                train.record(event, at(k), &mut output).unwrap();
+            }
+        }
+        let records: Vec<Train> = lines(&path)[1..]
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // timestamp and monotonic tell the same time, from when the session started
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        for train in records.iter() {
+            let started = train.timestamp - train.monotonic;
+            assert!((started - records[0].timestamp + records[0].monotonic).abs() < 1e-6);
+            assert!((started - now.as_secs_f64()).abs() < 5.0);
+        }
+        let records = records
+            .iter()
+            .map(|t| (t.monotonic, t.window_start, t.raw_expected, t.raw_received))
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        records
+    }
+
+    #[test]
+    fn test_train_timeline() {
+        // the first record starts with the first sample, in the middle of a second, and the
+        // dropped samples leave the next records where they belong
+        let expected = vec![
+            (0.5, 0, 256, 256),
+            (1.5, 256, 512, 412),
+            (2.5, 768, 512, 512),
+        ];
+        assert_eq!(timeline("live", Some(1.0)), expected);
+        // replayed four times as fast, the records keep the time of the recording
+        assert_eq!(timeline("fast", Some(4.0)), expected);
+        // replayed as fast as possible, each record spans one second
+        assert_eq!(timeline("max", None), expected);
+    }
 }
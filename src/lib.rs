@@ -1,9 +1,85 @@
+// Without the std feature only the parser is built, with no allocation, for microcontrollers
+// receiving straight from a ThinkGear module
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// Tests use std even when the library does not
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
+use core::fmt;
+use core::ops::Deref;
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+mod capture;
+#[cfg(feature = "std")]
+mod connection;
+#[cfg(feature = "std")]
+mod discovery;
+#[cfg(feature = "std")]
+mod dongle;
+#[cfg(feature = "std")]
+mod edf;
+#[cfg(feature = "std")]
+mod encoder;
+#[cfg(feature = "std")]
+mod error;
+#[cfg(feature = "std")]
+mod headset;
+#[cfg(feature = "std")]
+mod manager;
+#[cfg(feature = "std")]
+mod simulator;
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "std")]
+mod supervisor;
+#[cfg(feature = "std")]
+mod transport;
+
+#[cfg(feature = "std")]
+pub use capture::{Capture, Replay, Speed};
+#[cfg(feature = "std")]
+pub use connection::{connect_headset, open_dongle, ConfigCommand, ConnectionBuilder};
+#[cfg(feature = "std")]
+pub use discovery::{
+    find_dongle, list_dongles, probe, probe_dongle, DongleInfo, DONGLE_PID, DONGLE_VID,
+};
+#[cfg(feature = "std")]
+pub use dongle::{Dongle, DongleStatus};
+#[cfg(feature = "std")]
+pub use edf::{Annotation, EdfFile, EdfFormat, EdfSignal, EdfWriter};
+#[cfg(feature = "std")]
+pub use encoder::{checksum, encode_packet, encode_row, frame, write_row, EncodeError};
+#[cfg(feature = "std")]
+pub use error::Error;
+#[cfg(feature = "std")]
+pub use headset::{BandPower, Headset};
+#[cfg(feature = "std")]
+pub use manager::{SessionError, SessionInfo, SessionManager, TaggedEvent};
+#[cfg(feature = "std")]
+pub use simulator::{Scenario, Simulator, RAW_SAMPLE_RATE};
+#[cfg(feature = "async")]
+pub use stream::{connect_headset_async, PacketStream};
+#[cfg(feature = "std")]
+pub use supervisor::{Event, LossReason, PacketInfo, SessionEvent, Supervisor};
+#[cfg(feature = "std")]
+pub use transport::{
+    connect_transport, FileSource, ReadOnly, ReadWrite, Reopen, Source, StdinSource, Transport,
+};
+
+// Largest payload a packet can carry
+pub const MAX_PAYLOAD: usize = 170;
+
+// Longest row value kept inline in RowBytes, chosen so that PacketType takes at most 40 bytes
+pub const INLINE_ROW: usize = 30;
 
 pub const HEADSETID_AUTOCONNECT: [u8; 1] = [0xc2];
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum PacketType {
     HeadsetConnected(u16),
     HeadsetConnectedUndefined,
@@ -18,14 +94,127 @@ pub enum PacketType {
     StandbyPacketUndefined,
     StandbyLengthUndefined,
     PoorSignal(u8),
+    HeartRate(u8),
     Attention(u8),
     Meditation(u8),
+    Raw8Bit(u8),
+    RawMarker(u8),
     Blink(u8),
     RawValue(i16),
+    EegPower(EegPower),
     AsicEeg(AsicEeg),
+    // Milliseconds between two R-peaks
+    RrInterval(u16),
+    // Data row behind one or more EXCODE (0x55) bytes: extended code level, code and value bytes
+    ExtendedCode(u8, u8, RowBytes),
+    // Multi-byte data row with an unknown code or an unexpected VLENGTH: code and value bytes
+    RawRow(u8, RowBytes),
     PacketUndefined(u8),
 }
 
+// Value bytes of a data row. Values up to INLINE_ROW bytes are kept inline, so PacketType stays
+// small and needs no allocator; longer ones are boxed, which needs the std feature.
+#[derive(Clone)]
+pub struct RowBytes(RowRepr);
+
+#[derive(Clone)]
+enum RowRepr {
+    Inline(u8, [u8; INLINE_ROW]),
+    #[cfg(feature = "std")]
+    Boxed(Box<[u8]>),
+}
+
+impl RowBytes {
+    // None when `value` does not fit in a payload, or without std when it is longer than
+    // INLINE_ROW
+    pub fn from_slice(value: &[u8]) -> Option<RowBytes> {
+        if value.len() <= INLINE_ROW {
+            let mut bytes = [0; INLINE_ROW];
+            bytes[..value.len()].copy_from_slice(value);
+            return Some(RowBytes(RowRepr::Inline(value.len() as u8, bytes)));
+        }
+        #[cfg(feature = "std")]
+        if value.len() <= MAX_PAYLOAD {
+            return Some(RowBytes(RowRepr::Boxed(value.into())));
+        }
+        None
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match &self.0 {
+            RowRepr::Inline(len, bytes) => &bytes[..*len as usize],
+            #[cfg(feature = "std")]
+            RowRepr::Boxed(bytes) => bytes,
+        }
+    }
+}
+
+impl Deref for RowBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl PartialEq for RowBytes {
+    fn eq(&self, other: &RowBytes) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for RowBytes {}
+
+impl fmt::Debug for RowBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ParseError {
+    // Payload ends before the data row with this code is complete
+    TruncatedRow(u8),
+    // VLENGTH of the data row with this code runs past the end of payload
+    BadVlength(u8, u8),
+    PlengthTooLarge(u8),
+    // Expected (computed from payload) and received checksum
+    ChecksumMismatch(u8, u8),
+    // Without std, value of the data row with this code is longer than INLINE_ROW
+    RowTooLong(u8, u8),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TruncatedRow(code) => {
+                write!(f, "payload truncated in data row {:#04x}", code)
+            }
+            ParseError::BadVlength(code, vlength) => write!(
+                f,
+                "VLENGTH {} of data row {:#04x} exceeds payload",
+                vlength, code
+            ),
+            ParseError::PlengthTooLarge(plength) => {
+                write!(f, "plength {} larger than 170", plength)
+            }
+            ParseError::ChecksumMismatch(expected, found) => write!(
+                f,
+                "checksum mismatch, expected {:#04x} found {:#04x}",
+                expected, found
+            ),
+            ParseError::RowTooLong(code, vlength) => write!(
+                f,
+                "VLENGTH {} of data row {:#04x} too long to keep without std",
+                vlength, code
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
 pub enum State {
     NoSync,
     FirstSync,
@@ -33,7 +222,7 @@ pub enum State {
     ValidPacket,
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Default, Clone, Serialize, Deserialize)]
 pub struct AsicEeg {
     pub delta: u32,
     pub theta: u32,
@@ -60,11 +249,99 @@ impl AsicEeg {
     }
 }
 
+#[derive(PartialEq, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct EegPower {
+    pub delta: f32,
+    pub theta: f32,
+    pub low_alpha: f32,
+    pub high_alpha: f32,
+    pub low_beta: f32,
+    pub high_beta: f32,
+    pub low_gamma: f32,
+    pub mid_gamma: f32,
+}
+
+impl EegPower {
+    pub fn new() -> EegPower {
+        EegPower {
+            delta: 0.0,
+            theta: 0.0,
+            low_alpha: 0.0,
+            high_alpha: 0.0,
+            low_beta: 0.0,
+            high_beta: 0.0,
+            low_gamma: 0.0,
+            mid_gamma: 0.0,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(PartialEq, Debug)]
+pub struct Packet {
+    // Monotonic time at which the checksum byte was parsed
+    pub received: Instant,
+    // Counts every framed packet of the session, including the ones failing the checksum, so a
+    // gap between two packets means packets were dropped
+    pub sequence: u64,
+    pub payload: Vec<u8>,
+    pub checksum: u8,
+    pub rows: Vec<PacketType>,
+}
+
+#[cfg(feature = "std")]
+impl Packet {
+    pub fn plength(&self) -> u8 {
+        self.payload.len() as u8
+    }
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct ParserStats {
+    pub packets_ok: u64,
+    pub checksum_failures: u64,
+    pub plength_errors: u64,
+    // Packets with a matching checksum whose payload could not be decoded
+    pub row_errors: u64,
+    // Times sync was found again after discarding bytes
+    pub resyncs: u64,
+    pub bytes_discarded: u64,
+    pub undefined_codes: u64,
+    // Raw wave samples per second, measured over the last window of at least one second. Stays
+    // 0 without the std feature, which has no clock to measure with.
+    pub raw_rate: f64,
+}
+
+impl fmt::Display for ParserStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "packets ok {}, checksum failures {}, plength errors {}, row errors {}, resyncs {}, \
+             bytes discarded {}, undefined codes {}, raw rate {:.1} Hz",
+            self.packets_ok,
+            self.checksum_failures,
+            self.plength_errors,
+            self.row_errors,
+            self.resyncs,
+            self.bytes_discarded,
+            self.undefined_codes,
+            self.raw_rate
+        )
+    }
+}
+
 pub struct Parser {
     state: State,
     plength: u8,
-    payload: Vec<u8>,
+    payload: [u8; MAX_PAYLOAD],
+    payload_len: usize,
     checksum: u8,
+    sequence: u64,
+    stats: ParserStats,
+    discarding: bool,
+    // start of the current raw rate window and raw samples counted since
+    #[cfg(feature = "std")]
+    raw_window: Option<(Instant, u64)>,
 }
 
 impl Parser {
@@ -72,39 +349,124 @@ impl Parser {
         Parser {
             state: State::NoSync,
             plength: 0,
-            payload: Vec::new(),
+            payload: [0; MAX_PAYLOAD],
+            payload_len: 0,
             checksum: 0,
+            sequence: 0,
+            stats: ParserStats::default(),
+            discarding: false,
+            #[cfg(feature = "std")]
+            raw_window: None,
         }
     }
 }
 
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
+
 impl Parser {
-    pub fn parse(&mut self, data: u8) -> Option<Vec<PacketType>> {
+    #[cfg(feature = "std")]
+    pub fn parse(&mut self, data: u8) -> Result<Option<Packet>, ParseError> {
+        if self.push(data)? {
+            debug!("Checksum matched, start parsing");
+            let payload = self.payload().to_vec();
+            let rows = Rows::new(&payload)
+                .inspect(|r| self.record_row(r))
+                .collect::<Result<Vec<_>, _>>()?;
+            debug!("end of packet");
+            Ok(Some(Packet {
+                received: Instant::now(),
+                sequence: self.sequence - 1,
+                payload,
+                checksum: data,
+                rows,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Decodes a whole chunk of bytes read from the dongle. The payload buffer is reused between
+    // packets and rows are decoded lazily, so no allocation happens per packet. Bytes and rows
+    // not consumed before the iterator is dropped are discarded.
+    pub fn parse_slice<'a>(&'a mut self, data: &'a [u8]) -> ParseSlice<'a> {
+        ParseSlice {
+            parser: self,
+            data,
+            pos: 0,
+            row: None,
+        }
+    }
+
+    pub fn stats(&self) -> ParserStats {
+        self.stats
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.payload[..self.payload_len]
+    }
+
+    fn record_row(&mut self, row: &Result<PacketType, ParseError>) {
+        match row {
+            #[cfg(feature = "std")]
+            Ok(PacketType::RawValue(_)) => {
+                let now = Instant::now();
+                let (start, count) = self.raw_window.get_or_insert((now, 0));
+                *count += 1;
+                let elapsed = now.duration_since(*start);
+                if elapsed >= Duration::from_secs(1) {
+                    self.stats.raw_rate = *count as f64 / elapsed.as_secs_f64();
+                    self.raw_window = Some((now, 0));
+                }
+            }
+            Ok(PacketType::PacketUndefined(_)) | Ok(PacketType::RawRow(_, _)) => {
+                self.stats.undefined_codes += 1;
+            }
+            Err(_) => self.stats.row_errors += 1,
+            _ => (),
+        }
+    }
+
+    // Feeds one byte into the state machine, returns true once a packet with a matching
+    // checksum is available in the payload buffer
+    fn push(&mut self, data: u8) -> Result<bool, ParseError> {
         match self.state {
             State::NoSync => {
                 self.handle_nosync(data);
-                None
+                Ok(false)
             }
             State::FirstSync => {
                 self.handle_firstsync(data);
-                None
+                Ok(false)
             }
             State::SecondSync => {
-                self.handle_secondsync(data);
-                None
+                self.handle_secondsync(data)?;
+                Ok(false)
             }
             State::ValidPacket => self.handle_validpacket(data),
         }
     }
 
     fn reset(&mut self) {
-        *self = Parser::new();
+        self.state = State::NoSync;
+        self.plength = 0;
+        self.checksum = 0;
     }
 
     fn handle_nosync(&mut self, data: u8) {
         if data == 0xaa {
             self.state = State::FirstSync;
+            if self.discarding {
+                self.discarding = false;
+                self.stats.resyncs += 1;
+            }
             debug!("Standby for a valid packet");
+        } else {
+            self.discarding = true;
+            self.stats.bytes_discarded += 1;
         }
     }
 
@@ -114,222 +476,384 @@ impl Parser {
             debug!("Packet synced");
         } else {
             self.state = State::NoSync;
+            self.discarding = true;
+            self.stats.bytes_discarded += 1;
         }
     }
 
-    fn handle_secondsync(&mut self, data: u8) {
+    fn handle_secondsync(&mut self, data: u8) -> Result<(), ParseError> {
         if data > 0xaa {
             self.state = State::NoSync;
+            self.discarding = true;
+            self.stats.bytes_discarded += 1;
+            self.stats.plength_errors += 1;
             error!("Plength larger than 170!");
+            return Err(ParseError::PlengthTooLarge(data));
         } else if data < 0xaa {
             self.state = State::ValidPacket;
             self.plength = data;
+            self.payload_len = 0;
+            self.sequence += 1;
             debug!("Valid packet available, len({})", self.plength);
         }
+        Ok(())
     }
 
-    fn handle_validpacket(&mut self, data: u8) -> Option<Vec<PacketType>> {
+    fn handle_validpacket(&mut self, data: u8) -> Result<bool, ParseError> {
         if self.plength == 0 {
-            self.checksum = !self.checksum;
-            let re = if data != self.checksum {
+            let checksum = !self.checksum;
+            self.reset();
+            if data != checksum {
                 debug!("Checksum failed");
-                None
+                self.stats.checksum_failures += 1;
+                Err(ParseError::ChecksumMismatch(checksum, data))
             } else {
-                debug!("Checksum matched, start parsing");
-                Some(self.handle_parser())
-            };
-            self.reset();
-            re
+                self.stats.packets_ok += 1;
+                Ok(true)
+            }
         } else {
-            self.payload.push(data);
+            self.payload[self.payload_len] = data;
+            self.payload_len += 1;
             self.checksum = self.checksum.overflowing_add(data).0;
             self.plength -= 1;
-            None
+            Ok(false)
         }
     }
+}
+
+pub struct ParseSlice<'a> {
+    parser: &'a mut Parser,
+    data: &'a [u8],
+    pos: usize,
+    // position of the next data row in the payload of the last complete packet
+    row: Option<usize>,
+}
+
+impl<'a> ParseSlice<'a> {
+    // Sequence number of the packet the last row was decoded from, counted like
+    // Packet::sequence
+    pub fn sequence(&self) -> u64 {
+        self.parser.sequence.saturating_sub(1)
+    }
+}
 
-    fn handle_parser(&mut self) -> Vec<PacketType> {
-        let mut n = 0;
-        let mut result: Vec<PacketType> = Vec::new();
-        while n < self.payload.len() {
-            if self.payload[n] == 0xd0 {
-                // Headset Connected
-                if self.payload[n + 1] == 0x02 {
-                    info!(
-                        "headset connected, ID {:#04x} {:#04x}",
-                        self.payload[n + 2],
-                        self.payload[n + 3]
-                    );
-                    result.push(PacketType::HeadsetConnected(
-                        ((self.payload[n + 2] as u16) << 8) | (self.payload[n + 3] as u16),
-                    ));
-                } else {
-                    warn!("undefined packet while headset connected");
-                    result.push(PacketType::HeadsetConnectedUndefined);
-                }
+impl<'a> Iterator for ParseSlice<'a> {
+    type Item = Result<PacketType, ParseError>;
 
-                n += 4;
-            } else if self.payload[n] == 0xd1 {
-                // Headset Not Found
-                if self.payload[n + 1] == 0x02 {
-                    warn!(
-                        "Headset {:#04x} {:#04x} not found",
-                        self.payload[n + 2],
-                        self.payload[n + 3]
-                    );
-                    result.push(PacketType::HeadsetNotFound(
-                        ((self.payload[n + 2] as u16) << 8) | (self.payload[n + 3] as u16),
-                    ));
-                    n += 4;
-                } else if self.payload[n + 1] == 0x00 {
-                    warn!("no headset could be found during Connect All.");
-                    result.push(PacketType::NoHeadsetFound);
-                    n += 2;
-                } else {
-                    warn!("undefined packetLength while headset not found");
-                    result.push(PacketType::NotFoundUndefined);
-                }
-            } else if self.payload[n] == 0xd2 {
-                if self.payload[n + 1] == 0x02 {
-                    info!(
-                        "disconnected from headset {:#04x} {:#04x}",
-                        self.payload[n + 2],
-                        self.payload[n + 3]
-                    );
-                    result.push(PacketType::HeadsetDisconnected(
-                        ((self.payload[n + 2] as u16) << 8) | (self.payload[n + 3] as u16),
-                    ));
-                } else {
-                    warn!("undefined packetLength while headset disconnected");
-                    result.push(PacketType::HeadsetDisconnectedUndefined);
-                }
-                n += 4;
-            } else if self.payload[n] == 0xd3 {
-                if self.payload[n + 1] == 0x00 {
-                    warn!("the last command request was denied");
-                    result.push(PacketType::RequestDenied);
-                } else {
-                    warn!("undefined packetLength while headset disconnected");
-                    result.push(PacketType::HeadsetDisconnectedUndefined);
-                }
-                n += 2;
-            } else if self.payload[n] == 0xd4 {
-                if self.payload[n + 1] == 0x01 {
-                    if self.payload[n + 2] == 0x00 {
-                        debug!("headset is in standby mode awaiting for a command");
-                        result.push(PacketType::Standby);
-                    } else if self.payload[n + 2] == 0x01 {
-                        debug!("connecting to a headset");
-                        result.push(PacketType::FindHeadset);
-                    } else {
-                        warn!("undefined packet code while standby");
-                        result.push(PacketType::StandbyPacketUndefined);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(n) = self.row {
+                let mut rows = Rows {
+                    payload: self.parser.payload(),
+                    n,
+                };
+                match rows.next() {
+                    Some(r) => {
+                        self.row = Some(rows.n);
+                        self.parser.record_row(&r);
+                        return Some(r);
+                    }
+                    None => {
+                        debug!("end of packet");
+                        self.row = None;
                     }
-                } else {
-                    warn!("undefined packet length while standby");
-                    result.push(PacketType::StandbyLengthUndefined);
                 }
-                n += 3;
-            } else if self.payload[n] == 0x02 {
-                // poor signal
-                if self.payload[n + 1] == 200 {
-                    warn!("the ThinkGear contacts are not touching the user's skin");
-                } else {
-                    debug!("Poor signal quality {:#04x}", self.payload[n + 1]);
+            }
+            let data = *self.data.get(self.pos)?;
+            self.pos += 1;
+            match self.parser.push(data) {
+                Ok(true) => {
+                    debug!("Checksum matched, start parsing");
+                    self.row = Some(0);
                 }
-                result.push(PacketType::PoorSignal(self.payload[n + 1]));
-                n += 2;
-            } else if self.payload[n] == 0x04 {
-                // attention
-                debug!("Attention esense {:#04x}", self.payload[n + 1]);
-                result.push(PacketType::Attention(self.payload[n + 1]));
-                n += 2;
-            } else if self.payload[n] == 0x05 {
-                // meditation
-                debug!("Meditation esense {:#04x}", self.payload[n + 1]);
-                result.push(PacketType::Meditation(self.payload[n + 1]));
-                n += 2;
-            } else if self.payload[n] == 0x16 {
-                // blink
-                debug!("Blink strength {:#04x}", self.payload[n + 1]);
-                result.push(PacketType::Blink(self.payload[n + 1]));
-                n += 2;
-            } else if self.payload[n] == 0x80 {
-                // RAW Wave Value: a single big-endian 16-bit two's-compliment signed value
-                // (high-order byte followed by low-order byte) (-32768 to 32767)
-                let raw_val: i16 =
-                    ((self.payload[n + 2] as i16) << 8) | (self.payload[n + 3] as i16);
-                debug!("Raw value {:#04x}", raw_val);
-                result.push(PacketType::RawValue(raw_val));
-                n += 4;
-            } else if self.payload[n] == 0x83 {
-                //ASIC_EEG_POWER: eight big-endian 3-byte unsigned integer values representing
-                //delta, theta, low-alpha high-alpha, low-beta, high-beta, low-gamma, and mid-gamma
-                //EEG band power values
-                let mut eeg_vec: Vec<u32> = vec![];
-
-                for i in 0..8 {
-                    let asic = ((self.payload[n + 2 + i * 3] as u32) << 16)
-                        | ((self.payload[n + 3 + i * 3] as u32) << 8)
-                        | (self.payload[n + 4 + i * 3] as u32);
-                    eeg_vec.push(asic);
+                Ok(false) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// Iterates over the data rows of a payload, stops after the first error in the payload layout
+struct Rows<'a> {
+    payload: &'a [u8],
+    n: usize,
+}
+
+impl<'a> Rows<'a> {
+    #[cfg(feature = "std")]
+    fn new(payload: &'a [u8]) -> Rows<'a> {
+        Rows { payload, n: 0 }
+    }
+
+    fn fail(&mut self, e: ParseError) -> Option<Result<PacketType, ParseError>> {
+        self.n = self.payload.len();
+        Some(Err(e))
+    }
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = Result<PacketType, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let payload = self.payload;
+        let mut n = self.n;
+        if n >= payload.len() {
+            return None;
+        }
+        // any number of EXCODE bytes may precede the data row code
+        let mut level: u8 = 0;
+        while n < payload.len() && payload[n] == 0x55 {
+            level += 1;
+            n += 1;
+        }
+        if n == payload.len() {
+            warn!("EXCODE without data row at the end of payload");
+            return self.fail(ParseError::TruncatedRow(0x55));
+        }
+        // codes 0x80 and above are followed by a VLENGTH byte, the others by a single value byte
+        let code = payload[n];
+        let (start, len) = if code >= 0x80 {
+            match payload.get(n + 1) {
+                Some(&vlength) => (n + 2, vlength as usize),
+                None => {
+                    warn!("missing VLENGTH for packet code {:#04x}", code);
+                    return self.fail(ParseError::TruncatedRow(code));
                 }
+            }
+        } else {
+            (n + 1, 1)
+        };
+        let value = match payload.get(start..start + len) {
+            Some(value) => value,
+            None if code >= 0x80 => {
+                warn!(
+                    "VLENGTH {} exceeds payload for packet code {:#04x}",
+                    len, code
+                );
+                return self.fail(ParseError::BadVlength(code, len as u8));
+            }
+            None => {
+                warn!("missing value for packet code {:#04x}", code);
+                return self.fail(ParseError::TruncatedRow(code));
+            }
+        };
+        self.n = start + len;
+        if level > 0 {
+            debug!(
+                "Extended code level {} code {:#04x} value {:?}",
+                level, code, value
+            );
+            Some(row_bytes(code, value).map(|value| PacketType::ExtendedCode(level, code, value)))
+        } else {
+            Some(handle_row(code, value))
+        }
+    }
+}
 
-                let eeg_power = AsicEeg {
-                    delta: eeg_vec[0],
-                    theta: eeg_vec[1],
-                    low_alpha: eeg_vec[2],
-                    high_alpha: eeg_vec[3],
-                    low_beta: eeg_vec[4],
-                    high_beta: eeg_vec[5],
-                    low_gamma: eeg_vec[6],
-                    mid_gamma: eeg_vec[7],
-                };
-                debug!("EEG power values = {:?}", eeg_power);
-                result.push(PacketType::AsicEeg(eeg_power));
-                n += 26;
+fn handle_row(code: u8, value: &[u8]) -> Result<PacketType, ParseError> {
+    Ok(match code {
+        0xd0 => {
+            // Headset Connected
+            if value.len() == 2 {
+                info!("headset connected, ID {:#04x} {:#04x}", value[0], value[1]);
+                PacketType::HeadsetConnected(((value[0] as u16) << 8) | (value[1] as u16))
             } else {
-                warn!("packet code undefined {:#04x}", self.payload[n]);
-                result.push(PacketType::PacketUndefined(self.payload[n]));
-                n += 1;
+                warn!("undefined packet while headset connected");
+                PacketType::HeadsetConnectedUndefined
             }
         }
-        debug!("end of packet");
-        result
-    }
+        0xd1 => {
+            // Headset Not Found
+            if value.len() == 2 {
+                warn!("Headset {:#04x} {:#04x} not found", value[0], value[1]);
+                PacketType::HeadsetNotFound(((value[0] as u16) << 8) | (value[1] as u16))
+            } else if value.is_empty() {
+                warn!("no headset could be found during Connect All.");
+                PacketType::NoHeadsetFound
+            } else {
+                warn!("undefined packetLength while headset not found");
+                PacketType::NotFoundUndefined
+            }
+        }
+        0xd2 => {
+            if value.len() == 2 {
+                info!(
+                    "disconnected from headset {:#04x} {:#04x}",
+                    value[0], value[1]
+                );
+                PacketType::HeadsetDisconnected(((value[0] as u16) << 8) | (value[1] as u16))
+            } else {
+                warn!("undefined packetLength while headset disconnected");
+                PacketType::HeadsetDisconnectedUndefined
+            }
+        }
+        0xd3 => {
+            if value.is_empty() {
+                warn!("the last command request was denied");
+                PacketType::RequestDenied
+            } else {
+                warn!("undefined packetLength while headset disconnected");
+                PacketType::HeadsetDisconnectedUndefined
+            }
+        }
+        0xd4 => {
+            if value.len() == 1 {
+                if value[0] == 0x00 {
+                    debug!("headset is in standby mode awaiting for a command");
+                    PacketType::Standby
+                } else if value[0] == 0x01 {
+                    debug!("connecting to a headset");
+                    PacketType::FindHeadset
+                } else {
+                    warn!("undefined packet code while standby");
+                    PacketType::StandbyPacketUndefined
+                }
+            } else {
+                warn!("undefined packet length while standby");
+                PacketType::StandbyLengthUndefined
+            }
+        }
+        0x02 => {
+            // poor signal
+            if value[0] == 200 {
+                warn!("the ThinkGear contacts are not touching the user's skin");
+            } else {
+                debug!("Poor signal quality {:#04x}", value[0]);
+            }
+            PacketType::PoorSignal(value[0])
+        }
+        0x03 => {
+            // heart rate
+            debug!("Heart rate {}", value[0]);
+            PacketType::HeartRate(value[0])
+        }
+        0x04 => {
+            // attention
+            debug!("Attention esense {:#04x}", value[0]);
+            PacketType::Attention(value[0])
+        }
+        0x05 => {
+            // meditation
+            debug!("Meditation esense {:#04x}", value[0]);
+            PacketType::Meditation(value[0])
+        }
+        0x06 => {
+            // 8-bit raw wave value
+            debug!("8-bit raw value {:#04x}", value[0]);
+            PacketType::Raw8Bit(value[0])
+        }
+        0x07 => {
+            // raw marker: section start
+            debug!("Raw marker {:#04x}", value[0]);
+            PacketType::RawMarker(value[0])
+        }
+        0x16 => {
+            // blink
+            debug!("Blink strength {:#04x}", value[0]);
+            PacketType::Blink(value[0])
+        }
+        0x80 if value.len() == 2 => {
+            // RAW Wave Value: a single big-endian 16-bit two's-compliment signed value
+            // (high-order byte followed by low-order byte) (-32768 to 32767)
+            let raw_val: i16 = ((value[0] as i16) << 8) | (value[1] as i16);
+            debug!("Raw value {:#04x}", raw_val);
+            PacketType::RawValue(raw_val)
+        }
+        0x81 if value.len() == 32 => {
+            //EEG_POWER: eight big-endian 4-byte IEEE 754 floating point values representing
+            //delta, theta, low-alpha high-alpha, low-beta, high-beta, low-gamma, and mid-gamma
+            //EEG band power values
+            let mut eeg_vec = [0f32; 8];
+
+            for (i, eeg) in eeg_vec.iter_mut().enumerate() {
+                let bits = ((value[i * 4] as u32) << 24)
+                    | ((value[1 + i * 4] as u32) << 16)
+                    | ((value[2 + i * 4] as u32) << 8)
+                    | (value[3 + i * 4] as u32);
+                *eeg = f32::from_bits(bits);
+            }
+
+            let eeg_power = EegPower {
+                delta: eeg_vec[0],
+                theta: eeg_vec[1],
+                low_alpha: eeg_vec[2],
+                high_alpha: eeg_vec[3],
+                low_beta: eeg_vec[4],
+                high_beta: eeg_vec[5],
+                low_gamma: eeg_vec[6],
+                mid_gamma: eeg_vec[7],
+            };
+            debug!("EEG power values = {:?}", eeg_power);
+            PacketType::EegPower(eeg_power)
+        }
+        0x83 if value.len() == 24 => {
+            //ASIC_EEG_POWER: eight big-endian 3-byte unsigned integer values representing
+            //delta, theta, low-alpha high-alpha, low-beta, high-beta, low-gamma, and mid-gamma
+            //EEG band power values
+            let mut eeg_vec = [0u32; 8];
+
+            for (i, eeg) in eeg_vec.iter_mut().enumerate() {
+                *eeg = ((value[i * 3] as u32) << 16)
+                    | ((value[1 + i * 3] as u32) << 8)
+                    | (value[2 + i * 3] as u32);
+            }
+
+            let eeg_power = AsicEeg {
+                delta: eeg_vec[0],
+                theta: eeg_vec[1],
+                low_alpha: eeg_vec[2],
+                high_alpha: eeg_vec[3],
+                low_beta: eeg_vec[4],
+                high_beta: eeg_vec[5],
+                low_gamma: eeg_vec[6],
+                mid_gamma: eeg_vec[7],
+            };
+            debug!("EEG power values = {:?}", eeg_power);
+            PacketType::AsicEeg(eeg_power)
+        }
+        0x86 if value.len() == 2 => {
+            // RRINTERVAL: two byte big-endian unsigned integer representing the milliseconds
+            // between two R-peaks
+            let interval = ((value[0] as u16) << 8) | (value[1] as u16);
+            debug!("R-R interval {} ms", interval);
+            PacketType::RrInterval(interval)
+        }
+        0x80 | 0x81 | 0x83 | 0x86 => {
+            warn!(
+                "unexpected VLENGTH {} for packet code {:#04x}",
+                value.len(),
+                code
+            );
+            PacketType::RawRow(code, row_bytes(code, value)?)
+        }
+        0x80..=0xff => {
+            warn!("multi-byte packet code undefined {:#04x}", code);
+            PacketType::RawRow(code, row_bytes(code, value)?)
+        }
+        _ => {
+            warn!("packet code undefined {:#04x}", code);
+            PacketType::PacketUndefined(code)
+        }
+    })
 }
 
-pub fn connect_headset(
-    path: &str,
-    headset: &[u8],
-) -> Result<Box<dyn serialport::SerialPort>, &'static str> {
-    let mut port = serialport::new(path, 115_200)
-        .timeout(core::time::Duration::from_millis(1000))
-        .open()
-        .map_err(|_| "Cannot connect to dongle. Please make sure the serial number of your dongle is correct.")?;
-
-    const DISCONNECT: u8 = 0xc1;
-    const CONNECT: u8 = 0xc0;
-    let mut serial_buf: Vec<u8> = vec![0];
-
-    port.write(&[DISCONNECT])
-        .map_err(|_| "Failed to write DISCONNECT to dongle.")?;
-    port.read(serial_buf.as_mut_slice())
-        .map_err(|_| "Cannot read data from dongle.")?;
-    if headset.len() != 1 {
-        port.write(&[CONNECT])
-            .map_err(|_| "Failed to write CONNECT to dongle.")?;
-    }
-    port.write(&headset)
-        .map_err(|_| "Failed to write headset ID to dongle.")?;
-    return Ok(port);
+// Value bytes of a row always fit with std, as they are sliced out of a payload. Without std a
+// row too long to keep inline is skipped, and the rows after it are still decoded.
+fn row_bytes(code: u8, value: &[u8]) -> Result<RowBytes, ParseError> {
+    RowBytes::from_slice(value).ok_or_else(|| {
+        warn!("data row {:#04x} too long to keep without std", code);
+        ParseError::RowTooLong(code, value.len() as u8)
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
+    #[cfg(not(feature = "std"))]
+    use std::{format, vec, vec::Vec};
 
+    #[cfg(feature = "std")]
     #[test]
     fn test_parser() {
         let test_vec: Vec<u8> = vec![
@@ -373,8 +897,8 @@ mod tests {
         let mut result: Vec<PacketType> = Vec::new();
         let mut parser = Parser::new();
         for data in test_vec {
-            if let Some(x) = parser.parse(data) {
-                result = x;
+            if let Some(x) = parser.parse(data).unwrap() {
+                result = x.rows;
             }
         }
 
@@ -399,4 +923,379 @@ mod tests {
             ]
         );
     }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_parser_excode() {
+        let test_vec: Vec<u8> = vec![
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x0B, // [PLENGTH] (payload length) of 11 bytes
+            0x55, // [EXCODE] level 1
+            0x04, // [CODE] single byte value
+            0x2A, // value
+            0x55, // [EXCODE]
+            0x55, // [EXCODE] level 2
+            0x90, // [CODE] multi-byte value
+            0x02, // [VLENGTH] 2 bytes
+            0x12, // (1/2)
+            0x34, // (2/2)
+            0x04, // [ATTENTION] eSense
+            0x0D, // eSense Attention level of 13
+            0xE9, // [CHKSUM]
+        ];
+        let mut result: Vec<PacketType> = Vec::new();
+        let mut parser = Parser::new();
+        for data in test_vec {
+            if let Some(x) = parser.parse(data).unwrap() {
+                result = x.rows;
+            }
+        }
+
+        assert_eq!(
+            result,
+            vec![
+                PacketType::ExtendedCode(1, 0x04, RowBytes::from_slice(&[0x2a]).unwrap()),
+                PacketType::ExtendedCode(2, 0x90, RowBytes::from_slice(&[0x12, 0x34]).unwrap()),
+                PacketType::Attention(0x0d)
+            ]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_parser_vlength() {
+        let test_vec: Vec<u8> = vec![
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x0E, // [PLENGTH] (payload length) of 14 bytes
+            0x90, // undefined multi-byte code
+            0x03, // [VLENGTH] 3 bytes
+            0x01, // (1/3)
+            0x02, // (2/3)
+            0x03, // (3/3)
+            0x80, // [RAW Wave Value]
+            0x01, // [VLENGTH] 1 byte instead of 2
+            0x7F, // (1/1)
+            0x04, // [ATTENTION] eSense
+            0x0D, // eSense Attention level of 13
+            0x80, // [RAW Wave Value]
+            0x02, // [VLENGTH] 2 bytes
+            0xFF, // (1/2)
+            0xFE, // (2/2) raw value of -2
+            0xD6, // [CHKSUM]
+        ];
+        let mut result: Vec<PacketType> = Vec::new();
+        let mut parser = Parser::new();
+        for data in test_vec {
+            if let Some(x) = parser.parse(data).unwrap() {
+                result = x.rows;
+            }
+        }
+
+        assert_eq!(
+            result,
+            vec![
+                PacketType::RawRow(0x90, RowBytes::from_slice(&[0x01, 0x02, 0x03]).unwrap()),
+                PacketType::RawRow(0x80, RowBytes::from_slice(&[0x7f]).unwrap()),
+                PacketType::Attention(0x0d),
+                PacketType::RawValue(-2)
+            ]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_parser_errors() {
+        let mut parser = Parser::new();
+        let mut parse_all = |data: &[u8]| {
+            data.iter()
+                .map(|&b| parser.parse(b).map(|p| p.map(|p| p.rows)))
+                .find(|r| r != &Ok(None))
+                .unwrap_or(Ok(None))
+        };
+
+        assert_eq!(
+            // attention code without its value byte
+            parse_all(&[0xAA, 0xAA, 0x01, 0x04, 0xFB]),
+            Err(ParseError::TruncatedRow(0x04))
+        );
+        assert_eq!(
+            // ASIC_EEG_POWER declaring 24 bytes with only one present
+            parse_all(&[0xAA, 0xAA, 0x03, 0x83, 0x18, 0x00, 0x64]),
+            Err(ParseError::BadVlength(0x83, 0x18))
+        );
+        assert_eq!(
+            parse_all(&[0xAA, 0xAA, 0xAB]),
+            Err(ParseError::PlengthTooLarge(0xab))
+        );
+        assert_eq!(
+            parse_all(&[0xAA, 0xAA, 0x02, 0x04, 0x0D, 0x00]),
+            Err(ParseError::ChecksumMismatch(0xee, 0x00))
+        );
+        // the parser recovers and decodes the next packet
+        assert_eq!(
+            parse_all(&[0xAA, 0xAA, 0x02, 0x04, 0x0D, 0xEE]),
+            Ok(Some(vec![PacketType::Attention(0x0d)]))
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_parser_thinkgear_rows() {
+        let test_vec: Vec<u8> = vec![
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x2C, // [PLENGTH] (payload length) of 44 bytes
+            0x03, // [HEART_RATE]
+            0x48, // 72 beats per minute
+            0x06, // [8BIT_RAW]
+            0x7F, // 8-bit raw value
+            0x07, // [RAW_MARKER]
+            0x00, // section start
+            0x81, // [EEG_POWER]
+            0x20, // [VLENGTH] 32 bytes
+            0x3F, 0xC0, 0x00, 0x00, // Delta 1.5
+            0x40, 0x10, 0x00, 0x00, // Theta 2.25
+            0xBF, 0x00, 0x00, 0x00, // Low-alpha -0.5
+            0x42, 0xC8, 0x00, 0x00, // High-alpha 100.0
+            0x3E, 0x00, 0x00, 0x00, // Low-beta 0.125
+            0x40, 0x40, 0x00, 0x00, // High-beta 3.0
+            0x40, 0xF8, 0x00, 0x00, // Low-gamma 7.75
+            0x49, 0x74, 0x24, 0x00, // Mid-gamma 1000000.0
+            0x86, // [RRINTERVAL]
+            0x02, // [VLENGTH] 2 bytes
+            0x03, // (1/2)
+            0x20, // (2/2) 800 ms
+            0xED, // [CHKSUM]
+        ];
+        let mut result: Vec<PacketType> = Vec::new();
+        let mut parser = Parser::new();
+        for data in test_vec {
+            if let Some(x) = parser.parse(data).unwrap() {
+                result = x.rows;
+            }
+        }
+
+        let test_power = EegPower {
+            delta: 1.5,
+            theta: 2.25,
+            low_alpha: -0.5,
+            high_alpha: 100.0,
+            low_beta: 0.125,
+            high_beta: 3.0,
+            low_gamma: 7.75,
+            mid_gamma: 1_000_000.0,
+        };
+
+        assert_eq!(
+            result,
+            vec![
+                PacketType::HeartRate(72),
+                PacketType::Raw8Bit(0x7f),
+                PacketType::RawMarker(0x00),
+                PacketType::EegPower(test_power),
+                PacketType::RrInterval(800)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_slice() {
+        let test_vec: Vec<u8> = vec![
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x04, // [PLENGTH] (payload length) of 4 bytes
+            0x80, // [RAW Wave Value]
+            0x02, // [VLENGTH] 2 bytes
+            0x01, // (1/2)
+            0x02, // (2/2)
+            0x7A, // [CHKSUM]
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x02, // [PLENGTH] (payload length) of 2 bytes
+            0x04, // [ATTENTION] eSense
+            0x0D, // eSense Attention level of 13
+            0x00, // [CHKSUM] mismatch
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x04, // [PLENGTH] (payload length) of 4 bytes
+            0x04, // [ATTENTION] eSense
+            0x0D, // eSense Attention level of 13
+            0x05, // [MEDITATION] eSense
+            0x3D, // eSense Meditation level of 61
+            0xAC, // [CHKSUM]
+        ];
+        let expected = vec![
+            Ok(PacketType::RawValue(0x0102)),
+            Err(ParseError::ChecksumMismatch(0xee, 0x00)),
+            Ok(PacketType::Attention(0x0d)),
+            Ok(PacketType::Meditation(0x3d)),
+        ];
+
+        let mut parser = Parser::new();
+        let result: Vec<_> = parser.parse_slice(&test_vec).collect();
+        assert_eq!(result, expected);
+
+        // packets split across chunks decode the same way
+        let mut parser = Parser::new();
+        let mut result = Vec::new();
+        for chunk in test_vec.chunks(5) {
+            result.extend(parser.parse_slice(chunk));
+        }
+        assert_eq!(result, expected);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_parser_packet() {
+        let test_vec: Vec<u8> = vec![
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x02, // [PLENGTH] (payload length) of 2 bytes
+            0x04, // [ATTENTION] eSense
+            0x0D, // eSense Attention level of 13
+            0xEE, // [CHKSUM]
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x02, // [PLENGTH] (payload length) of 2 bytes
+            0x04, // [ATTENTION] eSense
+            0x0D, // eSense Attention level of 13
+            0x00, // [CHKSUM] mismatch, packet dropped
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x02, // [PLENGTH] (payload length) of 2 bytes
+            0x05, // [MEDITATION] eSense
+            0x3D, // eSense Meditation level of 61
+            0xBD, // [CHKSUM]
+        ];
+        let start = Instant::now();
+        let mut parser = Parser::new();
+        let packets: Vec<Packet> = test_vec
+            .into_iter()
+            .filter_map(|data| parser.parse(data).ok().flatten())
+            .collect();
+
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].sequence, 0);
+        assert_eq!(packets[0].payload, vec![0x04, 0x0d]);
+        assert_eq!(packets[0].checksum, 0xee);
+        assert_eq!(packets[0].rows, vec![PacketType::Attention(0x0d)]);
+        assert_eq!(packets[1].sequence, 2);
+        assert_eq!(packets[1].plength(), 2);
+        assert_eq!(packets[1].rows, vec![PacketType::Meditation(0x3d)]);
+        assert!(start <= packets[0].received && packets[0].received <= packets[1].received);
+    }
+
+    #[test]
+    fn test_parser_stats() {
+        let test_vec: Vec<u8> = vec![
+            0x12, // garbage before the first sync
+            0x34, // garbage before the first sync
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x03, // [PLENGTH] (payload length) of 3 bytes
+            0x04, // [ATTENTION] eSense
+            0x0D, // eSense Attention level of 13
+            0x42, // undefined code
+            0x00, // [CHKSUM] mismatch
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0xAB, // [PLENGTH] larger than 170
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x04, // [PLENGTH] (payload length) of 4 bytes
+            0x04, // [ATTENTION] eSense
+            0x0D, // eSense Attention level of 13
+            0x42, // undefined code
+            0x00, // value of undefined code
+            0xAC, // [CHKSUM]
+        ];
+        let mut parser = Parser::new();
+        let _: Vec<_> = parser.parse_slice(&test_vec).collect();
+
+        assert_eq!(
+            parser.stats(),
+            ParserStats {
+                packets_ok: 1,
+                checksum_failures: 1,
+                plength_errors: 1,
+                row_errors: 0,
+                resyncs: 2,
+                bytes_discarded: 3,
+                undefined_codes: 1,
+                raw_rate: 0.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_row_bytes() {
+        let value = RowBytes::from_slice(&[0x12, 0x34]).unwrap();
+        assert_eq!(value.as_slice(), &[0x12, 0x34]);
+        assert_eq!(value.len(), 2);
+        assert_eq!(format!("{:?}", value), "[18, 52]");
+        assert_eq!(
+            RowBytes::from_slice(&[7; INLINE_ROW]).unwrap().len(),
+            INLINE_ROW
+        );
+        assert_eq!(
+            RowBytes::from_slice(&[7; MAX_PAYLOAD]).map(|value| value.len()),
+            if cfg!(feature = "std") {
+                Some(MAX_PAYLOAD)
+            } else {
+                None
+            }
+        );
+        assert!(RowBytes::from_slice(&[0; MAX_PAYLOAD + 1]).is_none());
+
+        // rows are small enough to pass around at the raw sample rate
+        assert!(core::mem::size_of::<PacketType>() <= 40);
+    }
+
+    #[test]
+    fn test_parse_slice_rows() {
+        let mut test_vec: Vec<u8> = vec![
+            0xAA, // [SYNC]
+            0xAA, // [SYNC]
+            0x31, // [PLENGTH] (payload length) of 49 bytes
+            0x55, // [EXCODE] level 1
+            0x90, // [CODE] multi-byte value
+            0x02, // [VLENGTH] 2 bytes
+            0x12, // (1/2)
+            0x34, // (2/2)
+            0x91, // undefined multi-byte code
+            0x28, // [VLENGTH] 40 bytes
+        ];
+        test_vec.extend(core::iter::repeat(0x01).take(40));
+        test_vec.extend(&[
+            0x04, // [ATTENTION] eSense
+            0x0D, // eSense Attention level of 13
+            0xE0, // [CHKSUM]
+        ]);
+        let mut parser = Parser::new();
+        let result: Vec<_> = parser.parse_slice(&test_vec).collect();
+
+        let long_row = if cfg!(feature = "std") {
+            Ok(PacketType::RawRow(
+                0x91,
+                RowBytes::from_slice(&[0x01; 40]).unwrap(),
+            ))
+        } else {
+            // without std the long row cannot be kept, the next one is still decoded
+            Err(ParseError::RowTooLong(0x91, 40))
+        };
+        assert_eq!(
+            result,
+            vec![
+                Ok(PacketType::ExtendedCode(
+                    1,
+                    0x90,
+                    RowBytes::from_slice(&[0x12, 0x34]).unwrap()
+                )),
+                long_row,
+                Ok(PacketType::Attention(0x0d))
+            ]
+        );
+    }
 }
@@ -0,0 +1,361 @@
+use crate::{Error, Event, ParserStats, Source, Supervisor, Transport};
+use log::{info, warn};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// One of the sessions run by a SessionManager
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SessionInfo {
+    // Position of the session in the order the sessions were added
+    pub index: usize,
+    // Dongle path or other name of the source
+    pub label: String,
+    pub headset: Vec<u8>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct TaggedEvent {
+    pub session: Arc<SessionInfo>,
+    // When the session thread read the event
+    pub received: Instant,
+    pub event: Event,
+}
+
+// Error that ended a session; the other sessions keep running
+#[derive(Debug)]
+pub struct SessionError {
+    pub session: Arc<SessionInfo>,
+    pub error: Error,
+}
+
+enum Message {
+    Event(TaggedEvent),
+    // with the time the session failed
+    Error(SessionError, Instant),
+    Done,
+}
+
+// Event or error waiting in the reorder window, ordered so the heap pops the oldest first
+struct Pending {
+    received: Instant,
+    seq: u64,
+    item: Result<TaggedEvent, SessionError>,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Pending) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Pending) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Pending) -> CmpOrdering {
+        (other.received, other.seq).cmp(&(self.received, self.seq))
+    }
+}
+
+// Runs several supervised sessions, each on its own thread, and merges their events into one
+// stream ordered by the time they were read. Events are held back for a short reorder window
+// so that events read at nearly the same time on different threads come out in order.
+pub struct SessionManager {
+    sessions: Vec<Arc<SessionInfo>>,
+    // parser statistics of every session, updated by its thread after each event
+    stats: Vec<Arc<Mutex<ParserStats>>>,
+    sender: Sender<Message>,
+    receiver: Receiver<Message>,
+    pending: BinaryHeap<Pending>,
+    seq: u64,
+    live: usize,
+    window: Duration,
+    running: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl SessionManager {
+    pub fn new() -> SessionManager {
+        let (sender, receiver) = mpsc::channel();
+        SessionManager {
+            sessions: Vec::new(),
+            stats: Vec::new(),
+            sender,
+            receiver,
+            pending: BinaryHeap::new(),
+            seq: 0,
+            live: 0,
+            window: Duration::from_millis(100),
+            running: Arc::new(AtomicBool::new(true)),
+            threads: Vec::new(),
+        }
+    }
+
+    // Sets how long events are held back to be put in order
+    pub fn reorder_window(mut self, window: Duration) -> SessionManager {
+        self.window = window;
+        self
+    }
+
+    // Opens a supervised session on `source` connecting to `headset` and starts reading it
+    pub fn add(&mut self, source: Source, headset: &[u8]) -> Result<Arc<SessionInfo>, Error> {
+        let supervisor = source.supervise(headset)?;
+        Ok(self.add_supervisor(&source.label(), headset, supervisor))
+    }
+
+    // Starts reading a session that is already set up
+    pub fn add_supervisor<T: Transport + 'static>(
+        &mut self,
+        label: &str,
+        headset: &[u8],
+        supervisor: Supervisor<T>,
+    ) -> Arc<SessionInfo> {
+        let session = Arc::new(SessionInfo {
+            index: self.sessions.len(),
+            label: label.to_string(),
+            headset: headset.to_vec(),
+        });
+        self.sessions.push(session.clone());
+        let stats = Arc::new(Mutex::new(supervisor.stats()));
+        self.stats.push(stats.clone());
+        self.live += 1;
+
+        let info = session.clone();
+        let sender = self.sender.clone();
+        let running = self.running.clone();
+        // stopped also while waiting to reconnect
+        let mut supervisor = supervisor.stop_flag(running.clone());
+        self.threads.push(thread::spawn(move || {
+            info!("session {} started", info.label);
+            while running.load(Ordering::SeqCst) {
+                let event = supervisor.next_event();
+                *stats.lock().unwrap() = supervisor.stats();
+                let message = match event {
+                    Ok(Some(event)) => Message::Event(TaggedEvent {
+                        session: info.clone(),
+                        // rows carry the time their packet was read
+                        received: match &event {
+                            Event::Row(_, packet) => packet.received,
+                            _ => Instant::now(),
+                        },
+                        event,
+                    }),
+                    Ok(None) => break,
+                    Err(error) => {
+                        warn!("session {} failed: {}", info.label, error);
+                        let error = SessionError {
+                            session: info.clone(),
+                            error,
+                        };
+                        let _ = sender.send(Message::Error(error, Instant::now()));
+                        break;
+                    }
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+            let _ = sender.send(Message::Done);
+        }));
+        session
+    }
+
+    pub fn sessions(&self) -> &[Arc<SessionInfo>] {
+        &self.sessions
+    }
+
+    // Parser statistics of every session, in the order the sessions were added
+    pub fn stats(&self) -> Vec<(Arc<SessionInfo>, ParserStats)> {
+        self.sessions
+            .iter()
+            .zip(self.stats.iter())
+            .map(|(session, stats)| (session.clone(), *stats.lock().unwrap()))
+            .collect()
+    }
+
+    // Flag that keeps the sessions running. Clearing it, as from a Ctrl-C handler, stops every
+    // session, and next_event returns None once they have ended.
+    pub fn running(&self) -> Arc<AtomicBool> {
+        self.running.clone()
+    }
+
+    // Blocks until the next event of any session. A session error comes after the events the
+    // session read before it failed. Returns None once every session has ended.
+    pub fn next_event(&mut self) -> Option<Result<TaggedEvent, SessionError>> {
+        loop {
+            let wait = match self.pending.peek() {
+                Some(oldest) => {
+                    let release = oldest.received + self.window;
+                    let now = Instant::now();
+                    if release <= now || self.live == 0 {
+                        return self.pending.pop().map(|p| p.item);
+                    }
+                    release - now
+                }
+                None if self.live == 0 => return None,
+                None => self.window,
+            };
+            match self.receiver.recv_timeout(wait) {
+                Ok(Message::Event(event)) => self.hold(event.received, Ok(event)),
+                Ok(Message::Error(error, received)) => self.hold(received, Err(error)),
+                Ok(Message::Done) => self.live -= 1,
+                Err(RecvTimeoutError::Timeout) => (),
+                // the manager holds a sender itself
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            }
+        }
+    }
+
+    fn hold(&mut self, received: Instant, item: Result<TaggedEvent, SessionError>) {
+        self.seq += 1;
+        self.pending.push(Pending {
+            received,
+            seq: self.seq,
+            item,
+        });
+    }
+
+    // Stops every session and waits for their threads to finish
+    pub fn stop(self) {
+        self.running.store(false, Ordering::SeqCst);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Default for SessionManager {
+    fn default() -> SessionManager {
+        SessionManager::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::SimTransport;
+    use crate::{encode_packet, Dongle, PacketType, ReadOnly, Scenario, SessionEvent};
+    use pretty_assertions::assert_eq;
+    use std::io::{self, Cursor, Read};
+
+    // Stream that fails once read, like a dongle that was unplugged
+    struct Unplugged;
+
+    impl Read for Unplugged {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    fn sim_session(id: u16) -> Supervisor<SimTransport> {
+        Supervisor::new(
+            Dongle::new(SimTransport::new(id, Scenario::Normal)),
+            &id.to_be_bytes(),
+        )
+    }
+
+    #[test]
+    fn test_manager_merge() {
+        let mut manager = SessionManager::new().reorder_window(Duration::from_millis(50));
+        manager.add_supervisor("first", b"\xa0\x5f", sim_session(0xa05f));
+        manager.add_supervisor("second", b"\x12\x34", sim_session(0x1234));
+
+        let mut connected = vec![None; 2];
+        let mut last: Option<Instant> = None;
+        for _ in 0..5000 {
+            let tagged = manager.next_event().unwrap().unwrap();
+            let index = tagged.session.index;
+            if let Event::Session(SessionEvent::Connected(id)) = tagged.event {
+                connected[index] = Some(id);
+            }
+            // events of both sessions come out in the order they were read
+            if let Some(last) = last {
+                assert!(tagged.received >= last);
+            }
+            last = Some(tagged.received);
+        }
+        assert_eq!(connected, vec![Some(0xa05f), Some(0x1234)]);
+        assert_eq!(manager.sessions()[1].label, "second");
+        let stats = manager.stats();
+        assert_eq!(stats[0].0.label, "first");
+        assert!(stats.iter().all(|(_, stats)| stats.packets_ok > 0));
+        manager.stop();
+    }
+
+    #[test]
+    fn test_manager_end() {
+        let stream = encode_packet(&[PacketType::Attention(40)]).unwrap();
+        let mut manager = SessionManager::new();
+        for label in ["a", "b"].iter() {
+            let supervisor =
+                Supervisor::passive(Dongle::new(ReadOnly(Cursor::new(stream.clone()))));
+            manager.add_supervisor(label, &[], supervisor);
+        }
+        let mut events = Vec::new();
+        while let Some(tagged) = manager.next_event() {
+            let tagged = tagged.unwrap();
+            if let Event::Row(row, info) = tagged.event {
+                assert_eq!(tagged.received, info.received);
+                events.push((tagged.session.label.clone(), row, info.sequence));
+            }
+        }
+        events.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            events,
+            vec![
+                ("a".to_string(), PacketType::Attention(40), 0),
+                ("b".to_string(), PacketType::Attention(40), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_manager_error_order() {
+        let mut stream = Vec::new();
+        for value in 0..3 {
+            stream.extend(encode_packet(&[PacketType::Attention(value)]).unwrap());
+        }
+        let mut manager = SessionManager::new();
+        let transport = ReadOnly(Cursor::new(stream).chain(Unplugged));
+        manager.add_supervisor("a", &[], Supervisor::passive(Dongle::new(transport)));
+
+        // the rows read before the failure come first
+        let mut events = Vec::new();
+        while let Some(tagged) = manager.next_event() {
+            events.push(match tagged {
+                Ok(tagged) => format!("{:?}", tagged.event),
+                Err(e) => e.error.to_string(),
+            });
+        }
+        assert_eq!(events.len(), 4);
+        assert!(events[..3]
+            .iter()
+            .all(|event| event.starts_with("Row(Attention")));
+        assert!(events[3].starts_with("cannot read from or write to dongle"));
+    }
+
+    #[test]
+    fn test_manager_running() {
+        let mut manager = SessionManager::new();
+        manager.add_supervisor("first", b"\xa0\x5f", sim_session(0xa05f));
+        let running = manager.running();
+        for _ in 0..100 {
+            manager.next_event().unwrap().unwrap();
+        }
+        running.store(false, Ordering::SeqCst);
+        while let Some(tagged) = manager.next_event() {
+            tagged.unwrap();
+        }
+        manager.stop();
+    }
+}
@@ -1,11 +1,13 @@
-use clap::{App, Arg};
-use env_logger;
-use hex::decode;
+mod cli;
+
+use clap::App;
+use cli::SessionArgs;
+use log::warn;
 use minifb::{Key, Window, WindowOptions};
 use plotters::prelude::*;
 use plotters_bitmap::bitmap_pixel::BGRXPixel;
 use plotters_bitmap::BitMapBackend;
-use rustymind::{connect_headset, PacketType, Parser, HEADSETID_AUTOCONNECT};
+use rustymind::{Event, PacketType};
 use std::borrow::{Borrow, BorrowMut};
 use std::collections::VecDeque;
 use std::error::Error;
@@ -54,34 +56,19 @@ impl BorrowMut<[u32]> for BufferWrapper {
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
-    let matches = App::new("rustymind")
+    let app = App::new("rustymind")
         .version("1.0")
         .author("Junjun Dong <junjun.dong9@gmail.com>")
-        .about("parse mindwaves and draw real time plots")
-        .arg(
-            Arg::with_name("dongle-path")
-                .help("Sets the dongle path")
-                .required(true),
-        )
-        .arg(Arg::with_name("HEADSET_ID").help(
-            "Sets the headset ID. Set headset ID to 0xc2 to switch into auto-connect mode and connect to any to any headsets dongle can find",
-        ))
-        .get_matches();
+        .about("parse mindwaves and draw real time plots");
+    let matches = cli::source_args(app, &[]).get_matches();
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
     ctrlc::set_handler(move || {
         r.store(false, Ordering::SeqCst);
     })
     .expect("Error setting Ctrl-C handler");
-    let headset = matches
-        .value_of("HEADSET_ID")
-        .map_or(HEADSETID_AUTOCONNECT.to_vec(), |v| {
-            decode(v).expect("Hex decoding failed")
-        });
-    let path = matches.value_of("dongle-path").unwrap();
-    let mut port = connect_headset(path, &headset[..])?;
-    let mut read_buf: Vec<u8> = vec![0; 2048];
-    let mut parser = Parser::new();
+    let args = SessionArgs::from_matches(&matches)?;
+    let events = args.spawn()?;
     let mut esense = vec![VecDeque::new(); 2];
     let mut eeg = vec![VecDeque::new(); 8];
     let mut draw_buf = BufferWrapper(vec![0u32; W * H]);
@@ -132,9 +119,6 @@ fn main() -> Result<(), Box<dyn Error>> {
     drop(lower);
 
     while window.is_open() && !window.is_key_down(Key::Escape) && running.load(Ordering::SeqCst) {
-        let bytes_read = port.read(read_buf.as_mut_slice()).expect(
-            "Found no data when reading from dongle. Please make sure headset is connected.",
-        );
         let root = BitMapBackend::<BGRXPixel>::with_buffer_and_format(
             draw_buf.borrow_mut(),
             (W as u32, H as u32),
@@ -155,38 +139,37 @@ fn main() -> Result<(), Box<dyn Error>> {
             .bold_line_style(&GREEN.mix(0.2))
             .light_line_style(&TRANSPARENT)
             .draw()?;
-        for i in 0..bytes_read {
-            if let Some(x) = parser.parse(read_buf[i]) {
-                for r in x {
-                    match r {
-                        PacketType::Attention(value) => {
-                            esense[0].push_back(value as i32);
-                        }
-                        PacketType::Meditation(value) => {
-                            esense[1].push_back(value as i32);
-                        }
-                        PacketType::AsicEeg(value) => {
-                            eeg[0].push_back((value.delta / 10_000) as f64);
-                            eeg[1].push_back((value.theta / 10_000) as f64);
-                            eeg[2].push_back((value.low_alpha / 10_000) as f64);
-                            eeg[3].push_back((value.high_alpha / 10_000) as f64);
-                            eeg[4].push_back((value.low_beta / 10_000) as f64);
-                            eeg[5].push_back((value.high_beta / 10_000) as f64);
-                            eeg[6].push_back((value.low_gamma / 10_000) as f64);
-                            eeg[7].push_back((value.mid_gamma / 10_000) as f64);
-                        }
-                        _ => (),
-                    }
+        // the session runs on its own thread, so a reconnect never freezes the window
+        for event in events.try_iter() {
+            match event? {
+                Event::Row(PacketType::Attention(value), _) => {
+                    esense[0].push_back(value as i32);
+                }
+                Event::Row(PacketType::Meditation(value), _) => {
+                    esense[1].push_back(value as i32);
+                }
+                Event::Row(PacketType::AsicEeg(value), _) => {
+                    eeg[0].push_back((value.delta / 10_000) as f64);
+                    eeg[1].push_back((value.theta / 10_000) as f64);
+                    eeg[2].push_back((value.low_alpha / 10_000) as f64);
+                    eeg[3].push_back((value.high_alpha / 10_000) as f64);
+                    eeg[4].push_back((value.low_beta / 10_000) as f64);
+                    eeg[5].push_back((value.high_beta / 10_000) as f64);
+                    eeg[6].push_back((value.low_gamma / 10_000) as f64);
+                    eeg[7].push_back((value.mid_gamma / 10_000) as f64);
                 }
+                Event::Row(..) => (),
+                Event::Error(e) => warn!("{}", e),
+                Event::Session(e) => eprintln!("{:?}", e),
             }
         }
-        if esense[0].len() == 100 {
+        while esense[0].len() > 100 {
             esense[0].pop_front();
             esense[1].pop_front();
         }
-        if eeg[0].len() == 100 {
-            for n in 0..8 {
-                eeg[n].pop_front();
+        while eeg[0].len() > 100 {
+            for band in eeg.iter_mut() {
+                band.pop_front();
             }
         }
         for (idx, esense) in (0..).zip(esense.iter()) {
@@ -0,0 +1,63 @@
+use clap::{App, Arg};
+use hex::decode;
+use rustymind::{Scenario, Simulator};
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+    let matches = App::new("rustymind-sim")
+        .version("1.0")
+        .author("Junjun Dong <junjun.dong9@gmail.com>")
+        .about("emulate a MindWave dongle and headset on a pseudo-terminal")
+        .arg(
+            Arg::with_name("HEADSET_ID")
+                .help("Sets the ID of the simulated headset")
+                .default_value("a05f"),
+        )
+        .arg(
+            Arg::with_name("scenario")
+                .long("scenario")
+                .takes_value(true)
+                .possible_values(&["normal", "dropout", "not-found", "noisy", "out-of-range"])
+                .default_value("normal")
+                .help("Sets the simulated scenario"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("Sets the seed of the synthetic data"),
+        )
+        .get_matches();
+    let id = decode(matches.value_of("HEADSET_ID").unwrap()).expect("Hex decoding failed");
+    if id.len() != 2 {
+        return Err("Headset ID must be two bytes long".into());
+    }
+    let scenario: Scenario = matches.value_of("scenario").unwrap().parse()?;
+    let mut simulator = Simulator::new(((id[0] as u16) << 8) | (id[1] as u16), scenario);
+    if let Some(seed) = matches.value_of("seed") {
+        simulator = simulator.seed(seed.parse()?);
+    }
+    run(simulator)
+}
+
+#[cfg(unix)]
+fn run(mut simulator: Simulator) -> Result<(), Box<dyn Error>> {
+    use serialport::{SerialPort, TTYPort};
+    use std::time::Duration;
+
+    let (mut master, slave) = TTYPort::pair()?;
+    master.set_timeout(Duration::from_millis(10))?;
+    println!(
+        "Virtual dongle available at {}",
+        slave.name().unwrap_or_default()
+    );
+    simulator.run(&mut master)?;
+    drop(slave);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run(_simulator: Simulator) -> Result<(), Box<dyn Error>> {
+    Err("The simulator needs pseudo-terminals, which this platform does not provide".into())
+}
@@ -0,0 +1,419 @@
+use crate::{encode_packet, AsicEeg, PacketType};
+use log::{debug, info};
+use std::f32::consts::PI;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Raw wave samples per second sent by a MindWave headset
+pub const RAW_SAMPLE_RATE: u32 = 512;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Scenario {
+    // Good contact, every row at its nominal rate
+    Normal,
+    // The contacts leave the skin for 5 seconds out of every 20
+    SignalDropout,
+    // Connect requests are answered with "not found"
+    HeadsetNotFound,
+    // Poor contact: fluctuating signal quality, artifacts in the raw wave and corrupted bytes
+    NoisyContact,
+    // The headset goes out of range 30 seconds after connecting
+    OutOfRange,
+}
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Scenario, String> {
+        match s {
+            "normal" => Ok(Scenario::Normal),
+            "dropout" => Ok(Scenario::SignalDropout),
+            "not-found" => Ok(Scenario::HeadsetNotFound),
+            "noisy" => Ok(Scenario::NoisyContact),
+            "out-of-range" => Ok(Scenario::OutOfRange),
+            _ => Err(format!("unknown scenario {}", s)),
+        }
+    }
+}
+
+enum Link {
+    Standby,
+    // Scanning for the headset with this ID, or any headset, until the countdown in raw sample
+    // periods runs out
+    Scanning(Option<u16>, u32),
+    Connected,
+}
+
+// Emulates a MindWave dongle and headset: answers the dongle commands and produces the byte
+// stream the dongle sends, with synthetic raw wave, band power, eSense and blink rows
+pub struct Simulator {
+    headset_id: u16,
+    scenario: Scenario,
+    link: Link,
+    rng: u64,
+    // pending command bytes written by the host
+    command: Vec<u8>,
+    // raw sample periods elapsed since start and since the headset connected
+    tick: u64,
+    connected_ticks: u64,
+    blink: u32,
+}
+
+impl Simulator {
+    pub fn new(headset_id: u16, scenario: Scenario) -> Simulator {
+        Simulator {
+            headset_id,
+            scenario,
+            link: Link::Standby,
+            rng: 0x2545_f491_4f6c_dd1d,
+            command: Vec::new(),
+            tick: 0,
+            connected_ticks: 0,
+            blink: 0,
+        }
+    }
+
+    pub fn seed(mut self, seed: u64) -> Simulator {
+        // xorshift state must not be zero
+        self.rng = seed | 1;
+        self
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.link, Link::Connected)
+    }
+
+    // Handles command bytes written by the host and appends the immediate answers to `out`
+    pub fn command(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.command.extend_from_slice(data);
+        loop {
+            match self.command.first() {
+                Some(0xc0) if self.command.len() >= 3 => {
+                    let id = ((self.command[1] as u16) << 8) | (self.command[2] as u16);
+                    self.command.drain(..3);
+                    info!("connect to headset {:#06x}", id);
+                    self.scan(Some(id), out);
+                }
+                Some(0xc0) => break,
+                Some(0xc1) => {
+                    self.command.remove(0);
+                    info!("disconnect");
+                    if let Link::Connected = self.link {
+                        self.link = Link::Standby;
+                        packet(out, &[PacketType::HeadsetDisconnected(self.headset_id)]);
+                    } else {
+                        packet(out, &[PacketType::RequestDenied]);
+                    }
+                }
+                Some(0xc2) => {
+                    self.command.remove(0);
+                    info!("auto-connect");
+                    self.scan(None, out);
+                }
+                Some(&byte) => {
+                    self.command.remove(0);
+                    debug!("ignoring command byte {:#04x}", byte);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn scan(&mut self, id: Option<u16>, out: &mut Vec<u8>) {
+        if let Link::Connected = self.link {
+            packet(out, &[PacketType::RequestDenied]);
+            return;
+        }
+        packet(out, &[PacketType::FindHeadset]);
+        self.link = Link::Scanning(id, RAW_SAMPLE_RATE / 2);
+    }
+
+    // Appends the bytes the dongle sends during the next `samples` raw sample periods
+    pub fn advance(&mut self, samples: u32, out: &mut Vec<u8>) {
+        for _ in 0..samples {
+            self.step(out);
+        }
+    }
+
+    fn step(&mut self, out: &mut Vec<u8>) {
+        let second = self.tick % RAW_SAMPLE_RATE as u64 == 0;
+        self.tick += 1;
+        match self.link {
+            Link::Standby => {
+                if second {
+                    packet(out, &[PacketType::Standby]);
+                }
+            }
+            Link::Scanning(id, 0) => self.finish_scan(id, out),
+            Link::Scanning(id, remaining) => {
+                self.link = Link::Scanning(id, remaining - 1);
+                if second {
+                    packet(out, &[PacketType::FindHeadset]);
+                }
+            }
+            Link::Connected => self.stream(out),
+        }
+    }
+
+    fn finish_scan(&mut self, id: Option<u16>, out: &mut Vec<u8>) {
+        let found = self.scenario != Scenario::HeadsetNotFound
+            && id.unwrap_or(self.headset_id) == self.headset_id;
+        if found {
+            self.link = Link::Connected;
+            self.connected_ticks = 0;
+            packet(out, &[PacketType::HeadsetConnected(self.headset_id)]);
+        } else {
+            self.link = Link::Standby;
+            match id {
+                Some(id) => packet(out, &[PacketType::HeadsetNotFound(id)]),
+                None => packet(out, &[PacketType::NoHeadsetFound]),
+            }
+        }
+    }
+
+    fn stream(&mut self, out: &mut Vec<u8>) {
+        let t = self.connected_ticks;
+        self.connected_ticks += 1;
+        let seconds = t / RAW_SAMPLE_RATE as u64;
+        if self.scenario == Scenario::OutOfRange && seconds >= 30 {
+            info!("headset out of range");
+            self.link = Link::Standby;
+            packet(out, &[PacketType::HeadsetDisconnected(self.headset_id)]);
+            return;
+        }
+        let contact = !(self.scenario == Scenario::SignalDropout && seconds % 20 >= 15);
+
+        let start = out.len();
+        let raw = self.raw_sample(t, contact);
+        packet(out, &[PacketType::RawValue(raw)]);
+        if t % RAW_SAMPLE_RATE as u64 == RAW_SAMPLE_RATE as u64 - 1 {
+            let rows = self.second_rows(contact);
+            packet(out, &rows);
+            if contact && self.random(5) == 0 {
+                self.blink = RAW_SAMPLE_RATE / 4;
+                let strength = 30 + self.random(200) as u8;
+                packet(out, &[PacketType::Blink(strength)]);
+            }
+        }
+        if self.scenario == Scenario::NoisyContact && self.random(200) == 0 {
+            // flip a bit to corrupt the checksum or the framing
+            let n = start + self.random((out.len() - start) as u32) as usize;
+            out[n] ^= 1 << self.random(8);
+        }
+    }
+
+    fn raw_sample(&mut self, t: u64, contact: bool) -> i16 {
+        if !contact {
+            return 2048 - self.random(64) as i16;
+        }
+        let secs = t as f32 / RAW_SAMPLE_RATE as f32;
+        let mut value = 120.0 * (2.0 * PI * 10.0 * secs).sin()
+            + 80.0 * (2.0 * PI * 6.0 * secs).sin()
+            + 30.0 * (2.0 * PI * 20.0 * secs).sin()
+            + self.random(60) as f32
+            - 30.0;
+        if self.blink > 0 {
+            // eye blink artifact: a slow positive bump
+            let phase = self.blink as f32 / (RAW_SAMPLE_RATE / 4) as f32;
+            value += 600.0 * (PI * phase).sin();
+            self.blink -= 1;
+        }
+        if self.scenario == Scenario::NoisyContact && self.random(50) == 0 {
+            value += self.random(4000) as f32 - 2000.0;
+        }
+        value as i16
+    }
+
+    fn second_rows(&mut self, contact: bool) -> Vec<PacketType> {
+        if !contact {
+            return vec![
+                PacketType::PoorSignal(200),
+                PacketType::AsicEeg(AsicEeg::new()),
+                PacketType::Attention(0),
+                PacketType::Meditation(0),
+            ];
+        }
+        let poor_signal = match self.scenario {
+            Scenario::NoisyContact => 25 + self.random(125) as u8,
+            _ => 0,
+        };
+        let eeg = AsicEeg {
+            delta: 50_000 + self.random(1_000_000),
+            theta: 20_000 + self.random(300_000),
+            low_alpha: 5_000 + self.random(100_000),
+            high_alpha: 5_000 + self.random(100_000),
+            low_beta: 3_000 + self.random(60_000),
+            high_beta: 3_000 + self.random(60_000),
+            low_gamma: 1_000 + self.random(30_000),
+            mid_gamma: 1_000 + self.random(30_000),
+        };
+        vec![
+            PacketType::PoorSignal(poor_signal),
+            PacketType::AsicEeg(eeg),
+            PacketType::Attention(1 + self.random(100) as u8),
+            PacketType::Meditation(1 + self.random(100) as u8),
+        ]
+    }
+
+    // xorshift64* pseudo-random number in 0..bound
+    fn random(&mut self, bound: u32) -> u32 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let r = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        ((r >> 32) % bound.max(1) as u64) as u32
+    }
+
+    // Serves the host on `port` in real time until an I/O error occurs. Reads must time out (as
+    // serial ports do) so the stream keeps flowing while the host is silent.
+    pub fn run<T: Read + Write + ?Sized>(&mut self, port: &mut T) -> io::Result<()> {
+        let start = Instant::now();
+        let mut sent: u64 = 0;
+        let mut read_buf = [0u8; 64];
+        let mut out = Vec::new();
+        loop {
+            match port.read(&mut read_buf) {
+                Ok(n) => self.command(&read_buf[..n], &mut out),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+            let due = start.elapsed().as_secs_f64() * RAW_SAMPLE_RATE as f64;
+            let samples = due as u64 - sent;
+            self.advance(samples as u32, &mut out);
+            sent += samples;
+            if !out.is_empty() {
+                match port.write_all(&out) {
+                    Ok(()) => (),
+                    // nobody is reading, the dongle drops the data
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                    Err(e) => return Err(e),
+                }
+                out.clear();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn packet(out: &mut Vec<u8>, rows: &[PacketType]) {
+    match encode_packet(rows) {
+        Ok(bytes) => out.extend(bytes),
+        Err(e) => debug!("cannot encode simulated rows: {}", e),
+    }
+}
+
+// Transport answering through a simulator, advancing it until it has sent something
+#[cfg(test)]
+pub(crate) struct SimTransport {
+    pub sim: Simulator,
+    out: Vec<u8>,
+}
+
+#[cfg(test)]
+impl SimTransport {
+    pub fn new(headset_id: u16, scenario: Scenario) -> SimTransport {
+        SimTransport {
+            sim: Simulator::new(headset_id, scenario),
+            out: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Read for SimTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out.is_empty() {
+            self.sim.advance(16, &mut self.out);
+        }
+        let n = buf.len().min(self.out.len());
+        buf[..n].copy_from_slice(&self.out[..n]);
+        self.out.drain(..n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+impl Write for SimTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sim.command(buf, &mut self.out);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+    use pretty_assertions::assert_eq;
+
+    fn parse_all(parser: &mut Parser, data: &[u8]) -> Vec<PacketType> {
+        parser.parse_slice(data).filter_map(Result::ok).collect()
+    }
+
+    #[test]
+    fn test_simulator_connect() {
+        let mut sim = Simulator::new(0xa05f, Scenario::Normal);
+        let mut parser = Parser::new();
+        let mut out = Vec::new();
+
+        sim.advance(1, &mut out);
+        assert_eq!(parse_all(&mut parser, &out), vec![PacketType::Standby]);
+
+        out.clear();
+        sim.command(&[0xc0, 0xa0], &mut out);
+        sim.command(&[0x5f], &mut out);
+        sim.advance(RAW_SAMPLE_RATE, &mut out);
+        let rows = parse_all(&mut parser, &out);
+        assert_eq!(rows[0], PacketType::FindHeadset);
+        assert!(rows.contains(&PacketType::HeadsetConnected(0xa05f)));
+        assert!(sim.is_connected());
+
+        out.clear();
+        sim.advance(RAW_SAMPLE_RATE * 2, &mut out);
+        let rows = parse_all(&mut parser, &out);
+        let raw = rows
+            .iter()
+            .filter(|r| matches!(r, PacketType::RawValue(_)))
+            .count();
+        let attention = rows
+            .iter()
+            .filter(|r| matches!(r, PacketType::Attention(_)))
+            .count();
+        assert_eq!(raw, RAW_SAMPLE_RATE as usize * 2);
+        assert_eq!(attention, 2);
+        assert!(rows.contains(&PacketType::PoorSignal(0)));
+
+        out.clear();
+        sim.command(&[0xc1], &mut out);
+        assert_eq!(
+            parse_all(&mut parser, &out),
+            vec![PacketType::HeadsetDisconnected(0xa05f)]
+        );
+        assert_eq!(parser.stats().checksum_failures, 0);
+    }
+
+    #[test]
+    fn test_simulator_not_found() {
+        let mut sim = Simulator::new(0xa05f, Scenario::Normal);
+        let mut parser = Parser::new();
+        let mut out = Vec::new();
+
+        sim.command(&[0xc0, 0x12, 0x34], &mut out);
+        sim.advance(RAW_SAMPLE_RATE, &mut out);
+        assert!(parse_all(&mut parser, &out).contains(&PacketType::HeadsetNotFound(0x1234)));
+
+        let mut sim = Simulator::new(0xa05f, Scenario::HeadsetNotFound);
+        out.clear();
+        sim.command(&[0xc2], &mut out);
+        sim.advance(RAW_SAMPLE_RATE, &mut out);
+        assert!(parse_all(&mut parser, &out).contains(&PacketType::NoHeadsetFound));
+        assert!(!sim.is_connected());
+    }
+}
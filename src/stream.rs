@@ -0,0 +1,229 @@
+use crate::{DongleStatus, Error, Packet, Parser};
+use futures_core::Stream;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+const CONNECT: u8 = 0xc0;
+const DISCONNECT: u8 = 0xc1;
+const AUTO_CONNECT: u8 = 0xc2;
+
+// Stream of the packets read from an async byte source, such as a tokio-serial port. Every
+// bit of state lives in the stream, so dropping a pending `next_packet` or `poll_next` loses
+// no data: the next call continues where the dropped one stopped.
+pub struct PacketStream<R> {
+    reader: R,
+    parser: Parser,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    done: bool,
+    timeout: Duration,
+}
+
+impl<R> PacketStream<R> {
+    pub fn new(reader: R) -> PacketStream<R> {
+        PacketStream {
+            reader,
+            parser: Parser::new(),
+            buf: vec![0; 2048],
+            pos: 0,
+            len: 0,
+            done: false,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    // Sets how long to wait for the dongle to answer a command
+    pub fn timeout(mut self, timeout: Duration) -> PacketStream<R> {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn parser(&self) -> &Parser {
+        &self.parser
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    // Gives the byte source back; bytes read but not parsed yet are dropped
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: AsyncRead + Unpin> PacketStream<R> {
+    // Next packet, or None once the source has ended. Cancellation safe.
+    pub async fn next_packet(&mut self) -> Option<Result<Packet, Error>> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl<R: AsyncRead + AsyncWrite + Unpin> PacketStream<R> {
+    // Connects to the headset with the given two byte ID, or to any headset when given
+    // HEADSETID_AUTOCONNECT, dropping the current connection first
+    pub async fn connect_headset(&mut self, headset: &[u8]) -> Result<DongleStatus, Error> {
+        let command = match headset {
+            [hi, lo] => vec![CONNECT, *hi, *lo],
+            [AUTO_CONNECT] => vec![AUTO_CONNECT],
+            _ => return Err(Error::InvalidHeadsetId(headset.len())),
+        };
+        self.disconnect().await?;
+        self.command(&command).await?;
+        self.wait(|s| {
+            matches!(
+                s,
+                DongleStatus::Connected(_) | DongleStatus::NotFound(_) | DongleStatus::Denied
+            )
+        })
+        .await
+    }
+
+    pub async fn disconnect(&mut self) -> Result<DongleStatus, Error> {
+        self.command(&[DISCONNECT]).await?;
+        self.wait(|s| {
+            matches!(
+                s,
+                DongleStatus::Disconnected(_) | DongleStatus::Denied | DongleStatus::Standby
+            )
+        })
+        .await
+    }
+
+    // Disconnects the headset and gives the byte source back
+    pub async fn shutdown(mut self) -> Result<R, Error> {
+        self.disconnect().await?;
+        self.reader.shutdown().await?;
+        Ok(self.reader)
+    }
+
+    async fn command(&mut self, command: &[u8]) -> Result<(), Error> {
+        self.reader.write_all(command).await?;
+        self.reader.flush().await?;
+        Ok(())
+    }
+
+    async fn wait<F: Fn(&DongleStatus) -> bool>(&mut self, done: F) -> Result<DongleStatus, Error> {
+        let timeout = self.timeout;
+        let answer = async {
+            while let Some(packet) = self.next_packet().await {
+                let packet = match packet {
+                    Ok(packet) => packet,
+                    Err(Error::Parse(_)) => continue,
+                    Err(e) => return Err(e),
+                };
+                for row in packet.rows.iter() {
+                    if let Some(status) = DongleStatus::from_row(row) {
+                        if done(&status) {
+                            return Ok(status);
+                        }
+                    }
+                }
+            }
+            Err(Error::Closed)
+        };
+        tokio::time::timeout(timeout, answer)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for PacketStream<R> {
+    type Item = Result<Packet, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            while this.pos < this.len {
+                let data = this.buf[this.pos];
+                this.pos += 1;
+                match this.parser.parse(data) {
+                    Ok(Some(packet)) => return Poll::Ready(Some(Ok(packet))),
+                    Ok(None) => (),
+                    Err(e) => return Poll::Ready(Some(Err(Error::Parse(e)))),
+                }
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            let mut read_buf = ReadBuf::new(&mut this.buf);
+            match Pin::new(&mut this.reader).poll_read(cx, &mut read_buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(())) => {
+                    let n = read_buf.filled().len();
+                    if n == 0 {
+                        this.done = true;
+                    }
+                    this.pos = 0;
+                    this.len = n;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(Error::Io(e)))),
+            }
+        }
+    }
+}
+
+// Opens the dongle as an async serial port and connects to `headset` once the dongle confirms
+// the connection. Must be called from within a tokio runtime.
+pub async fn connect_headset_async(
+    path: &str,
+    headset: &[u8],
+) -> Result<PacketStream<SerialStream>, Error> {
+    let port = tokio_serial::new(path, 115_200).open_native_async()?;
+    let mut stream = PacketStream::new(port);
+    match stream.connect_headset(headset).await? {
+        DongleStatus::Connected(_) => Ok(stream),
+        DongleStatus::Denied => Err(Error::Denied),
+        DongleStatus::NotFound(id) => Err(Error::NotFound(id)),
+        status => Err(Error::UnexpectedStatus(status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_packet, PacketType};
+    use pretty_assertions::assert_eq;
+    use std::future::Future;
+    use std::task::Waker;
+
+    // Polls a future that never waits, as reading from a slice never does
+    fn ready<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        match future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+        {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future is pending"),
+        }
+    }
+
+    #[test]
+    fn test_packet_stream() {
+        let mut data = encode_packet(&[PacketType::Attention(40)]).unwrap();
+        data.extend_from_slice(&[0xaa, 0xaa, 0x02, 0x04, 0x0d, 0x00]);
+        data.extend(encode_packet(&[PacketType::Meditation(60)]).unwrap());
+        let mut stream = PacketStream::new(&data[..]);
+
+        let packet = ready(stream.next_packet()).unwrap().unwrap();
+        assert_eq!(packet.rows, vec![PacketType::Attention(40)]);
+        assert!(matches!(
+            ready(stream.next_packet()),
+            Some(Err(Error::Parse(_)))
+        ));
+        let packet = ready(stream.next_packet()).unwrap().unwrap();
+        assert_eq!(packet.rows, vec![PacketType::Meditation(60)]);
+        assert!(ready(stream.next_packet()).is_none());
+        assert_eq!(stream.parser().stats().packets_ok, 2);
+    }
+}
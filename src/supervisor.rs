@@ -0,0 +1,476 @@
+use crate::{Dongle, DongleStatus, Error, PacketType, ParseError, Parser, ParserStats, Transport};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Why a session stopped receiving data
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum LossReason {
+    // The dongle reported the headset disconnected
+    Disconnected,
+    // The dongle went back to standby
+    Standby,
+    // No data arrived within the read timeout
+    Timeout,
+    // The headset reported no contact for too long
+    PoorSignal,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SessionEvent {
+    Connected(u16),
+    Lost(LossReason),
+    Reconnected(u16),
+    // Reconnecting failed as many times as allowed
+    GaveUp,
+}
+
+// Packet a row was decoded from
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct PacketInfo {
+    // Counted like Packet::sequence, so a gap between the rows of two packets means packets
+    // were dropped
+    pub sequence: u64,
+    // Monotonic time the chunk ending the packet was read
+    pub received: Instant,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Event {
+    Row(PacketType, PacketInfo),
+    Error(ParseError),
+    Session(SessionEvent),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum SessionState {
+    Connecting,
+    Streaming,
+    GaveUp,
+    Ended,
+}
+
+// Keeps a headset session alive: connects, watches the stream for disconnects, timeouts and
+// prolonged poor signal, and reconnects with exponential backoff. Recorded sources are read
+// passively, without commands or reconnection.
+pub struct Supervisor<T> {
+    dongle: Dongle<T>,
+    headset: Option<Vec<u8>>,
+    parser: Parser,
+    buf: Vec<u8>,
+    events: VecDeque<Event>,
+    state: SessionState,
+    connected: bool,
+    attempts: u32,
+    backoff: Duration,
+    last_data: Instant,
+    poor_signal: u32,
+    read_timeout: Duration,
+    poor_signal_limit: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: Option<u32>,
+    running: Option<Arc<AtomicBool>>,
+}
+
+impl<T: Transport> Supervisor<T> {
+    // Supervises the connection to `headset`, two ID bytes or HEADSETID_AUTOCONNECT
+    pub fn new(dongle: Dongle<T>, headset: &[u8]) -> Supervisor<T> {
+        let mut supervisor = Supervisor::passive(dongle);
+        supervisor.headset = Some(headset.to_vec());
+        supervisor.state = SessionState::Connecting;
+        supervisor
+    }
+
+    // Reads a session that is already streaming, such as a recording
+    pub fn passive(dongle: Dongle<T>) -> Supervisor<T> {
+        Supervisor {
+            dongle,
+            headset: None,
+            parser: Parser::new(),
+            buf: vec![0; 2048],
+            events: VecDeque::new(),
+            state: SessionState::Streaming,
+            connected: false,
+            attempts: 0,
+            backoff: Duration::from_secs(1),
+            last_data: Instant::now(),
+            poor_signal: 0,
+            read_timeout: Duration::from_secs(5),
+            poor_signal_limit: 60,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_attempts: None,
+            running: None,
+        }
+    }
+
+    // Sets how long the stream may stay silent before the connection counts as lost
+    pub fn read_timeout(mut self, timeout: Duration) -> Supervisor<T> {
+        self.read_timeout = timeout;
+        self
+    }
+
+    // Sets how many poor signal reports of 200 in a row, about one per second, count as
+    // lost contact
+    pub fn poor_signal_limit(mut self, reports: u32) -> Supervisor<T> {
+        self.poor_signal_limit = reports;
+        self
+    }
+
+    // Sets the delay before the second connect attempt, doubled after every failure up to
+    // `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Supervisor<T> {
+        self.initial_backoff = initial;
+        self.backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    // Gives up after this many failed connect attempts in a row instead of retrying forever
+    pub fn max_attempts(mut self, attempts: u32) -> Supervisor<T> {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    // Ends the session once `running` is cleared, also while waiting to reconnect: next_event
+    // then returns None. A read already waiting for data still finishes first.
+    pub fn stop_flag(mut self, running: Arc<AtomicBool>) -> Supervisor<T> {
+        self.running = Some(running);
+        self
+    }
+
+    pub fn stats(&self) -> ParserStats {
+        self.parser.stats()
+    }
+
+    pub fn dongle(&mut self) -> &mut Dongle<T> {
+        &mut self.dongle
+    }
+
+    // Blocks until the next row, parse error or session event. Returns None at the end of a
+    // recorded stream.
+    pub fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            if !self.is_running() {
+                return Ok(None);
+            }
+            match self.state {
+                SessionState::Ended => return Ok(None),
+                SessionState::GaveUp => return Err(Error::GaveUp),
+                _ => self.step()?,
+            }
+        }
+    }
+
+    // Makes one connect attempt or one read and returns the events it produced, which may be
+    // none
+    pub fn poll(&mut self) -> Result<std::collections::vec_deque::Drain<'_, Event>, Error> {
+        if self.events.is_empty() && self.is_running() {
+            match self.state {
+                SessionState::GaveUp => return Err(Error::GaveUp),
+                SessionState::Ended => (),
+                _ => self.step()?,
+            }
+        }
+        Ok(self.events.drain(..))
+    }
+
+    // True once a recorded stream has ended
+    pub fn is_finished(&self) -> bool {
+        self.state == SessionState::Ended && self.events.is_empty()
+    }
+
+    fn is_running(&self) -> bool {
+        self.running
+            .as_ref()
+            .map_or(true, |running| running.load(Ordering::SeqCst))
+    }
+
+    fn step(&mut self) -> Result<(), Error> {
+        match self.state {
+            SessionState::Connecting => {
+                self.connect();
+                Ok(())
+            }
+            SessionState::Streaming => self.read(),
+            _ => Ok(()),
+        }
+    }
+
+    fn connect(&mut self) {
+        let headset = match &self.headset {
+            Some(headset) => headset,
+            None => return,
+        };
+        if self.attempts > 0 {
+            info!("reconnecting in {:?}", self.backoff);
+            // in short steps, to notice being stopped
+            let until = Instant::now() + self.backoff;
+            loop {
+                if !self.is_running() {
+                    return;
+                }
+                let left = until.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                thread::sleep(left.min(Duration::from_millis(50)));
+            }
+            self.backoff = (self.backoff * 2).min(self.max_backoff);
+        }
+        self.attempts += 1;
+
+        // the dongle may still hold the lost connection
+        let _ = self.dongle.disconnect();
+        match self.dongle.connect_headset(headset) {
+            Ok(DongleStatus::Connected(id)) => {
+                let event = if self.connected {
+                    SessionEvent::Reconnected(id)
+                } else {
+                    SessionEvent::Connected(id)
+                };
+                self.events.push_back(Event::Session(event));
+                self.state = SessionState::Streaming;
+                self.connected = true;
+                self.attempts = 0;
+                self.backoff = self.initial_backoff;
+                self.last_data = Instant::now();
+                self.poor_signal = 0;
+                return;
+            }
+            Ok(status) => warn!("connect attempt {} failed: {:?}", self.attempts, status),
+            Err(e) => warn!("connect attempt {} failed: {}", self.attempts, e),
+        }
+        if self.max_attempts.is_some_and(|max| self.attempts >= max) {
+            self.events.push_back(Event::Session(SessionEvent::GaveUp));
+            self.state = SessionState::GaveUp;
+        }
+    }
+
+    fn read(&mut self) -> Result<(), Error> {
+        let mut lost = None;
+        match self.dongle.read(&mut self.buf) {
+            Ok(0) if self.headset.is_none() => self.state = SessionState::Ended,
+            Ok(0) => return Err(Error::Closed),
+            Ok(n) => {
+                self.last_data = Instant::now();
+                let mut rows = self.parser.parse_slice(&self.buf[..n]);
+                while let Some(r) = rows.next() {
+                    let info = PacketInfo {
+                        sequence: rows.sequence(),
+                        received: self.last_data,
+                    };
+                    match r {
+                        Ok(row) => {
+                            match row {
+                                PacketType::HeadsetDisconnected(_) => {
+                                    lost = Some(LossReason::Disconnected)
+                                }
+                                PacketType::Standby => lost = Some(LossReason::Standby),
+                                PacketType::PoorSignal(200) => {
+                                    self.poor_signal += 1;
+                                    if self.poor_signal >= self.poor_signal_limit {
+                                        lost = Some(LossReason::PoorSignal);
+                                    }
+                                }
+                                PacketType::PoorSignal(_) => self.poor_signal = 0,
+                                _ => (),
+                            }
+                            self.events.push_back(Event::Row(row, info));
+                        }
+                        Err(e) => self.events.push_back(Event::Error(e)),
+                    }
+                }
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            // such as an unplugged dongle, reconnected like a headset that went away
+            Err(e) if self.headset.is_some() => {
+                warn!("cannot read from dongle: {}", e);
+                lost = Some(LossReason::Disconnected);
+            }
+            Err(e) => return Err(Error::Io(e)),
+        }
+        if self.headset.is_some() && self.last_data.elapsed() >= self.read_timeout {
+            lost = Some(LossReason::Timeout);
+        }
+        if let Some(reason) = lost {
+            self.lose(reason);
+        }
+        Ok(())
+    }
+
+    fn lose(&mut self, reason: LossReason) {
+        self.events
+            .push_back(Event::Session(SessionEvent::Lost(reason)));
+        self.poor_signal = 0;
+        // a recording only reports what happened
+        if self.headset.is_some() {
+            warn!("connection lost: {:?}", reason);
+            self.state = SessionState::Connecting;
+            self.attempts = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::SimTransport;
+    use crate::{encode_packet, ReadOnly, Reopen, Scenario};
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Simulated dongle whose reads fail after a few, like a dongle that was unplugged
+    struct Unplugged {
+        dongle: SimTransport,
+        reads: u32,
+    }
+
+    impl Read for Unplugged {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.reads == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.reads -= 1;
+            self.dongle.read(buf)
+        }
+    }
+
+    impl Write for Unplugged {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.dongle.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.dongle.flush()
+        }
+    }
+
+    fn sessions<T: Transport>(supervisor: &mut Supervisor<T>, count: usize) -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+        while events.len() < count {
+            if let Some(Event::Session(event)) = supervisor.next_event().unwrap() {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn test_supervisor_reconnect() {
+        let mut supervisor = Supervisor::new(
+            Dongle::new(SimTransport::new(0xa05f, Scenario::OutOfRange)),
+            b"\xa0\x5f",
+        );
+        assert_eq!(
+            sessions(&mut supervisor, 5),
+            vec![
+                SessionEvent::Connected(0xa05f),
+                SessionEvent::Lost(LossReason::Disconnected),
+                SessionEvent::Reconnected(0xa05f),
+                SessionEvent::Lost(LossReason::Disconnected),
+                SessionEvent::Reconnected(0xa05f),
+            ]
+        );
+
+        let mut supervisor = Supervisor::new(
+            Dongle::new(SimTransport::new(0xa05f, Scenario::SignalDropout)),
+            b"\xa0\x5f",
+        )
+        .poor_signal_limit(3);
+        assert_eq!(
+            sessions(&mut supervisor, 3),
+            vec![
+                SessionEvent::Connected(0xa05f),
+                SessionEvent::Lost(LossReason::PoorSignal),
+                SessionEvent::Reconnected(0xa05f),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_supervisor_reopen() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        let port: Box<dyn Transport> = Box::new(Unplugged {
+            dongle: SimTransport::new(0xa05f, Scenario::Normal),
+            reads: 20,
+        });
+        let transport = Reopen::new(port, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let port: Box<dyn Transport> = Box::new(SimTransport::new(0xa05f, Scenario::Normal));
+            Ok(port)
+        });
+        let mut supervisor = Supervisor::new(Dongle::new(transport), b"\xa0\x5f");
+        assert_eq!(
+            sessions(&mut supervisor, 3),
+            vec![
+                SessionEvent::Connected(0xa05f),
+                SessionEvent::Lost(LossReason::Disconnected),
+                SessionEvent::Reconnected(0xa05f),
+            ]
+        );
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+
+        // the reopened port keeps streaming
+        let mut rows = 0;
+        while rows < 100 {
+            if let Some(Event::Row(..)) = supervisor.next_event().unwrap() {
+                rows += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_supervisor_gave_up() {
+        let mut supervisor = Supervisor::new(
+            Dongle::new(SimTransport::new(0xa05f, Scenario::HeadsetNotFound)),
+            b"\xa0\x5f",
+        )
+        .backoff(Duration::from_millis(1), Duration::from_millis(2))
+        .max_attempts(3);
+        assert_eq!(
+            supervisor.next_event().unwrap(),
+            Some(Event::Session(SessionEvent::GaveUp))
+        );
+        assert!(matches!(supervisor.next_event(), Err(Error::GaveUp)));
+    }
+
+    #[test]
+    fn test_supervisor_passive() {
+        let mut stream = encode_packet(&[PacketType::Attention(40)]).unwrap();
+        stream.extend(encode_packet(&[PacketType::HeadsetDisconnected(0xa05f)]).unwrap());
+        let mut supervisor = Supervisor::passive(Dongle::new(ReadOnly(&stream[..])));
+        let mut rows = Vec::new();
+        let mut sessions = Vec::new();
+        while let Some(event) = supervisor.next_event().unwrap() {
+            match event {
+                Event::Row(row, info) => rows.push((row, info.sequence)),
+                Event::Session(event) => sessions.push(event),
+                Event::Error(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(
+            rows,
+            vec![
+                (PacketType::Attention(40), 0),
+                (PacketType::HeadsetDisconnected(0xa05f), 1),
+            ]
+        );
+        assert_eq!(sessions, vec![SessionEvent::Lost(LossReason::Disconnected)]);
+        assert!(supervisor.is_finished());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{Capture, ConnectionBuilder, Dongle, Error, Replay, Speed, Supervisor};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Stdin, Write};
+use std::path::{Path, PathBuf};
+
+// Byte stream between the host and a dongle. Anything that can be read from and written to
+// is a transport, serial ports included.
+pub trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send + ?Sized> Transport for T {}
+
+// Transport over a source that only produces bytes, such as a recorded capture or stdin.
+// Commands written to it are discarded.
+pub struct ReadOnly<R>(pub R);
+
+impl<R: Read> Read for ReadOnly<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R> Write for ReadOnly<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Transport reading from and writing to two separate halves, such as the two ends of a pipe
+pub struct ReadWrite<R, W> {
+    pub reader: R,
+    pub writer: W,
+}
+
+impl<R, W> ReadWrite<R, W> {
+    pub fn new(reader: R, writer: W) -> ReadWrite<R, W> {
+        ReadWrite { reader, writer }
+    }
+}
+
+impl<R: Read, W> Read for ReadWrite<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R, W: Write> Write for ReadWrite<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Transport opening its source again after a read or write failed, such as a dongle that was
+// unplugged and plugged back in. The failed call still returns its error; the next call opens
+// the source first and fails with NotConnected while it cannot be opened.
+pub struct Reopen<T> {
+    inner: Option<T>,
+    open: Box<dyn FnMut() -> Result<T, Error> + Send>,
+}
+
+impl<T: Transport> Reopen<T> {
+    pub fn new<F>(inner: T, open: F) -> Reopen<T>
+    where
+        F: FnMut() -> Result<T, Error> + Send + 'static,
+    {
+        Reopen {
+            inner: Some(inner),
+            open: Box::new(open),
+        }
+    }
+
+    fn inner(&mut self) -> io::Result<&mut T> {
+        if self.inner.is_none() {
+            let inner = (self.open)()
+                .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e.to_string()))?;
+            self.inner = Some(inner);
+        }
+        self.inner
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    fn check<R>(&mut self, result: io::Result<R>) -> io::Result<R> {
+        if let Err(e) = &result {
+            // timeouts are how a quiet port answers
+            if !matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ) {
+                self.inner = None;
+            }
+        }
+        result
+    }
+}
+
+impl<T: Transport> Read for Reopen<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner()?.read(buf);
+        self.check(result)
+    }
+}
+
+impl<T: Transport> Write for Reopen<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner()?.write(buf);
+        self.check(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner()?.flush();
+        self.check(result)
+    }
+}
+
+pub type FileSource = ReadOnly<File>;
+pub type StdinSource = ReadOnly<Stdin>;
+
+// Where the bytes of a session come from
+#[derive(PartialEq, Debug, Clone)]
+pub enum Source {
+    // Dongle device path
+    Serial(String),
+    // Recorded byte stream
+    File(PathBuf),
+    Stdin,
+    // Capture file, replayed at the given speed
+    Replay(PathBuf, Speed),
+}
+
+impl Source {
+    // Opens the source and connects the dongle to `headset`. Recorded sources skip the
+    // handshake and are read from their first byte.
+    pub fn open(&self, headset: &[u8]) -> Result<Box<dyn Transport>, Error> {
+        match self {
+            Source::Serial(path) => crate::connect_headset(path, headset),
+            _ => self.transport(),
+        }
+    }
+
+    // Opens the source for a supervised session. The dongle is connected to `headset` and
+    // reconnected whenever the connection is lost, opening its port again if reading it
+    // failed; recorded sources are read as they are.
+    pub fn supervise(&self, headset: &[u8]) -> Result<Supervisor<Box<dyn Transport>>, Error> {
+        Ok(self.supervisor(self.transport()?, headset))
+    }
+
+    // Like supervise, also writing every byte read from the source, with the time it was read,
+    // to a capture file at `path` for a later Source::Replay
+    pub fn capture(
+        &self,
+        headset: &[u8],
+        path: &Path,
+    ) -> Result<Supervisor<Box<dyn Transport>>, Error> {
+        let writer = BufWriter::new(File::create(path)?);
+        let transport = Capture::new(self.transport()?, writer)?;
+        Ok(self.supervisor(Box::new(transport), headset))
+    }
+
+    // Dongle path, path of the recording or stdin
+    pub fn label(&self) -> String {
+        match self {
+            Source::Serial(path) => path.clone(),
+            Source::File(path) | Source::Replay(path, _) => path.display().to_string(),
+            Source::Stdin => "stdin".to_string(),
+        }
+    }
+
+    // Opens the source without sending any command
+    fn transport(&self) -> Result<Box<dyn Transport>, Error> {
+        Ok(match self {
+            Source::Serial(path) => {
+                let builder = ConnectionBuilder::new(path);
+                let port = builder.open_port()?;
+                Box::new(Reopen::new(port, move || builder.open_port()))
+            }
+            Source::File(path) => Box::new(ReadOnly(File::open(path)?)),
+            Source::Stdin => Box::new(ReadOnly(io::stdin())),
+            Source::Replay(path, speed) => {
+                let file = BufReader::new(File::open(path)?);
+                Box::new(ReadOnly(Replay::new(file, *speed)?))
+            }
+        })
+    }
+
+    fn supervisor(
+        &self,
+        transport: Box<dyn Transport>,
+        headset: &[u8],
+    ) -> Supervisor<Box<dyn Transport>> {
+        match self {
+            Source::Serial(_) => Supervisor::new(Dongle::new(transport), headset),
+            _ => Supervisor::passive(Dongle::new(transport)),
+        }
+    }
+}
+
+// Sends the dongle commands connecting to `headset` over any transport without waiting for
+// the dongle to answer, for transports such as recorded streams that cannot answer
+pub fn connect_transport<T: Transport>(mut port: T, headset: &[u8]) -> Result<T, Error> {
+    const DISCONNECT: u8 = 0xc1;
+    const CONNECT: u8 = 0xc0;
+    let mut serial_buf: Vec<u8> = vec![0];
+
+    port.write_all(&[DISCONNECT])?;
+    let _ = port.read(serial_buf.as_mut_slice())?;
+    if headset.len() != 1 {
+        port.write_all(&[CONNECT])?;
+    }
+    port.write_all(headset)?;
+    Ok(port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_packet, PacketType, Parser, HEADSETID_AUTOCONNECT};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_connect_transport() {
+        let stream = encode_packet(&[PacketType::Standby]).unwrap();
+        let transport = ReadWrite::new(&stream[..], Vec::new());
+        let transport = connect_transport(transport, b"\xa0\x5f").unwrap();
+        assert_eq!(transport.writer, vec![0xc1, 0xc0, 0xa0, 0x5f]);
+
+        let transport = ReadWrite::new(&stream[..], Vec::new());
+        let transport = connect_transport(transport, &HEADSETID_AUTOCONNECT).unwrap();
+        assert_eq!(transport.writer, vec![0xc1, 0xc2]);
+    }
+
+    #[test]
+    fn test_open_recorded() {
+        let mut stream = encode_packet(&[PacketType::Standby]).unwrap();
+        stream.extend(encode_packet(&[PacketType::HeadsetConnected(0xa05f)]).unwrap());
+        let path = std::env::temp_dir().join(format!("rustymind-{}.bin", std::process::id()));
+        std::fs::write(&path, &stream).unwrap();
+
+        // no handshake byte is taken from the recording, so the first packet comes through
+        let mut transport = Source::File(path.clone()).open(b"\xa0\x5f").unwrap();
+        let mut buf = Vec::new();
+        transport.read_to_end(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(buf, stream);
+        let mut parser = Parser::new();
+        let rows: Vec<_> = parser.parse_slice(&buf).filter_map(Result::ok).collect();
+        assert_eq!(
+            rows,
+            vec![PacketType::Standby, PacketType::HeadsetConnected(0xa05f)]
+        );
+    }
+}